@@ -3,7 +3,7 @@ extern crate pgbuild;
 use std::fs::File;
 use std::path::Path;
 use std::env;
-use std::io::Write;
+use std::io::{Write, BufRead, BufReader};
 
 fn main() {
     // pgbuild functions are unsafe for no reason
@@ -12,6 +12,25 @@ fn main() {
         let major = pgbuild::pg_version() / 100;
         let minor = pgbuild::pg_version() % 100;
         println!("cargo:rustc-cfg=postgres=\"{}.{}\"", major, minor);
+        if pgbuild::pg_version() >= 1400 {
+            println!("cargo:rustc-cfg=multirange");
+        }
+        // hash_any and friends became static inline wrappers in 13
+        if pgbuild::pg_version() >= 1300 {
+            println!("cargo:rustc-cfg=hash_bytes");
+        }
+        // ereport passes the error location to errfinish instead of errstart since 13
+        if pgbuild::pg_version() >= 1300 {
+            println!("cargo:rustc-cfg=errfinish_location");
+        }
+        // ErrorData gained a backtrace after context in 13
+        if pgbuild::pg_version() >= 1300 {
+            println!("cargo:rustc-cfg=errordata_backtrace");
+        }
+        // soft error reporting, many input functions grew an escontext argument in 16
+        if pgbuild::pg_version() >= 1600 {
+            println!("cargo:rustc-cfg=escontext");
+        }
 
         let mut f = File::create(Path::new(&env::var("OUT_DIR").unwrap()).join("basedefs.rs")).unwrap();
         writeln!(f, "const PG_VERSION: usize = {};", pgbuild::pg_version()).unwrap();
@@ -22,15 +41,211 @@ fn main() {
         writeln!(f, "const FLOAT8_BYVAL: bool = {};", pgbuild::float8_byval() != 0).unwrap();
         writeln!(f, "const LEN_SCANKEYDATA: usize = {};", pgbuild::len_scankeydata()).unwrap();
         writeln!(f, "const LEN_SIGJMPBUF: usize = {};", pgbuild::len_sigjmpbuf()).unwrap();
+        writeln!(f, "const MAXIMUM_ALIGNOF: usize = {};", pgbuild::maximum_alignof()).unwrap();
+        writeln!(f, "const ALIGNOF_DOUBLE: usize = {};", pgbuild::alignof_double()).unwrap();
+
+        writeln!(f, "const ELEVEL_DEBUG5: i32 = {};", pgbuild::elevel_debug5()).unwrap();
+        writeln!(f, "const ELEVEL_DEBUG4: i32 = {};", pgbuild::elevel_debug4()).unwrap();
+        writeln!(f, "const ELEVEL_DEBUG3: i32 = {};", pgbuild::elevel_debug3()).unwrap();
+        writeln!(f, "const ELEVEL_DEBUG2: i32 = {};", pgbuild::elevel_debug2()).unwrap();
+        writeln!(f, "const ELEVEL_DEBUG1: i32 = {};", pgbuild::elevel_debug1()).unwrap();
+        writeln!(f, "const ELEVEL_LOG: i32 = {};", pgbuild::elevel_log()).unwrap();
+        writeln!(f, "const ELEVEL_LOG_SERVER_ONLY: i32 = {};", pgbuild::elevel_log_server_only()).unwrap();
+        writeln!(f, "const ELEVEL_INFO: i32 = {};", pgbuild::elevel_info()).unwrap();
+        writeln!(f, "const ELEVEL_NOTICE: i32 = {};", pgbuild::elevel_notice()).unwrap();
+        writeln!(f, "const ELEVEL_WARNING: i32 = {};", pgbuild::elevel_warning()).unwrap();
+        writeln!(f, "const ELEVEL_ERROR: i32 = {};", pgbuild::elevel_error()).unwrap();
+        writeln!(f, "const ELEVEL_FATAL: i32 = {};", pgbuild::elevel_fatal()).unwrap();
+        writeln!(f, "const ELEVEL_PANIC: i32 = {};", pgbuild::elevel_panic()).unwrap();
 
         writeln!(f, "const CACHEID_TYPEOID: i32 = {};", pgbuild::cacheid_typeoid()).unwrap();
+        writeln!(f, "const CACHEID_ENUMOID: i32 = {};", pgbuild::cacheid_enumoid()).unwrap();
+        writeln!(f, "const CACHEID_ENUMTYPOIDNAME: i32 = {};", pgbuild::cacheid_enumtypoidname()).unwrap();
 
         writeln!(f, "const RELATT_OFFSET: usize = {};", pgbuild::relatt_offset()).unwrap();
         writeln!(f, "const RS_CBUF_OFFSET: usize = {};", pgbuild::rs_cbuf_offset()).unwrap();
         writeln!(f, "const XS_CBUF_OFFSET: usize = {};", pgbuild::xs_cbuf_offset()).unwrap();
+        writeln!(f, "const RD_OPFAMILY_OFFSET: usize = {};", pgbuild::rd_opfamily_offset()).unwrap();
+        writeln!(f, "const RD_OPCINTYPE_OFFSET: usize = {};", pgbuild::rd_opcintype_offset()).unwrap();
+        writeln!(f, "const RD_INDEX_OFFSET: usize = {};", pgbuild::rd_index_offset()).unwrap();
+        writeln!(f, "const INDNKEYATTS_OFFSET: usize = {};", pgbuild::indnkeyatts_offset()).unwrap();
+
+        writeln!(f, "const T_FUNCEXPR: i32 = {};", pgbuild::t_funcexpr()).unwrap();
+        writeln!(f, "const T_OPEXPR: i32 = {};", pgbuild::t_opexpr()).unwrap();
+        writeln!(f, "const FUNCEXPR_ARGS_OFFSET: usize = {};", pgbuild::funcexpr_args_offset()).unwrap();
+        writeln!(f, "const OPEXPR_ARGS_OFFSET: usize = {};", pgbuild::opexpr_args_offset()).unwrap();
+        writeln!(f, "const LIST_ELEMENTS_OFFSET: usize = {};", pgbuild::list_elements_offset()).unwrap();
+    }
+
+    generate_sqlstates(&Path::new(&env::var("OUT_DIR").unwrap()).join("sqlstate.rs"));
+    generate_encodings(&Path::new(&env::var("OUT_DIR").unwrap()).join("encoding.rs"));
+}
 
+// compare MAKE_SQLSTATE in elog.h
+fn make_sqlstate(code: &str) -> i32 {
+    code.bytes().enumerate().map(|(i, c)| ((c.wrapping_sub(b'0') & 0x3f) as i32) << (6 * i)).sum()
+}
+
+// ERRCODE_UNIQUE_VIOLATION -> UniqueViolation (with prefix "ERRCODE_")
+fn camel_case(name: &str, prefix: &str) -> String {
+    name.trim_start_matches(prefix).split('_').map(|word| {
+        let mut chars = word.chars();
+        chars.next().into_iter().chain(chars.flat_map(char::to_lowercase)).collect::<String>()
+    }).collect()
+}
+
+// "Integrity Constraint Violation" -> integrity_constraint_violation
+fn snake_case(description: &str) -> String {
+    let description = description.split(" (").next().unwrap();
+    let words: Vec<String> = description.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_ascii_lowercase())
+        .collect();
+    words.join("_")
+}
 
-        assert_ne!(pgbuild::float4_byval(), 0);
-        assert_ne!(pgbuild::float8_byval(), 0);
+fn generate_sqlstates(out: &Path) {
+    let input = BufReader::new(File::open(pgbuild::errcodes_txt()).unwrap());
+
+    let mut classes = Vec::new(); // (class code, description)
+    let mut codes = Vec::new(); // (sqlstate, variant name)
+    let mut aliases = Vec::new(); // (alias name, sqlstate)
+
+    for line in input.lines() {
+        let line = line.unwrap();
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        if line.starts_with("Section: Class ") {
+            let section = &line["Section: Class ".len()..];
+            let mut parts = section.splitn(2, " - ");
+            let class = parts.next().unwrap().to_owned();
+            classes.push((class, snake_case(parts.next().unwrap())));
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.len() {
+            4 => codes.push((fields[0].to_owned(), camel_case(fields[2], "ERRCODE_"))),
+            // codes without a spec name are duplicates of existing ones
+            3 => aliases.push((camel_case(fields[2], "ERRCODE_"), fields[0].to_owned())),
+            _ => panic!("unexpected line in errcodes.txt: {}", line),
+        }
     }
+
+    let mut f = File::create(out).unwrap();
+    writeln!(f, "#[repr(i32)]").unwrap();
+    writeln!(f, "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]").unwrap();
+    writeln!(f, "pub enum SqlState {{").unwrap();
+    for &(ref code, ref name) in &codes {
+        writeln!(f, "    {} = {}, // {}", name, make_sqlstate(code), code).unwrap();
+    }
+    writeln!(f, "}}").unwrap();
+    writeln!(f).unwrap();
+
+    writeln!(f, "#[allow(non_upper_case_globals)]").unwrap();
+    writeln!(f, "impl SqlState {{").unwrap();
+    for &(ref alias, ref code) in &aliases {
+        let &(_, ref name) = codes.iter().find(|&&(ref c, _)| c == code).unwrap();
+        writeln!(f, "    pub const {}: SqlState = SqlState::{};", alias, name).unwrap();
+    }
+    writeln!(f).unwrap();
+
+    writeln!(f, "    pub fn from_sqlerrcode(sqlerrcode: i32) -> Option<SqlState> {{").unwrap();
+    writeln!(f, "        match sqlerrcode {{").unwrap();
+    for &(ref code, ref name) in &codes {
+        writeln!(f, "            {} => Some(SqlState::{}),", make_sqlstate(code), name).unwrap();
+    }
+    writeln!(f, "            _ => None,").unwrap();
+    writeln!(f, "        }}").unwrap();
+    writeln!(f, "    }}").unwrap();
+    writeln!(f).unwrap();
+
+    writeln!(f, "    pub fn code(self) -> &'static str {{").unwrap();
+    writeln!(f, "        match self {{").unwrap();
+    for &(ref code, ref name) in &codes {
+        writeln!(f, "            SqlState::{} => \"{}\",", name, code).unwrap();
+    }
+    writeln!(f, "        }}").unwrap();
+    writeln!(f, "    }}").unwrap();
+
+    for &(ref class, ref description) in &classes {
+        writeln!(f).unwrap();
+        writeln!(f, "    pub fn is_{}(self) -> bool {{", description).unwrap();
+        writeln!(f, "        self.category() == {}", make_sqlstate(&format!("{}000", class))).unwrap();
+        writeln!(f, "    }}").unwrap();
+    }
+    writeln!(f, "}}").unwrap();
+}
+
+// parses enum pg_enc out of mb/pg_wchar.h
+fn generate_encodings(out: &Path) {
+    let input = BufReader::new(File::open(pgbuild::pg_wchar_h()).unwrap());
+
+    let mut encodings = Vec::new(); // (name without PG_, value)
+    let mut be_last = None;
+    let mut in_enum = false;
+    let mut next_value = 0;
+
+    for line in input.lines() {
+        let line = line.unwrap();
+        let code = line.split("/*").next().unwrap().trim();
+
+        if code.starts_with("typedef enum pg_enc") {
+            in_enum = true;
+        } else if in_enum && code.starts_with('}') {
+            in_enum = false;
+        } else if in_enum && code.starts_with("PG_") {
+            let mut parts = code.trim_end_matches(',').splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            if let Some(value) = parts.next() {
+                next_value = value.trim().parse().unwrap();
+            }
+            encodings.push((name.trim_start_matches("PG_").to_owned(), next_value));
+            next_value += 1;
+        } else if code.starts_with("#define PG_ENCODING_BE_LAST") {
+            be_last = code.split_whitespace().nth(2).map(|name| name.trim_start_matches("PG_").to_owned());
+        }
+    }
+    assert!(!encodings.is_empty(), "enum pg_enc not found in pg_wchar.h");
+    let be_last = encodings.iter().find(|&&(ref name, _)| Some(name) == be_last.as_ref())
+        .expect("PG_ENCODING_BE_LAST not found in pg_wchar.h").1;
+
+    let mut f = File::create(out).unwrap();
+    writeln!(f, "#[repr(i32)]").unwrap();
+    writeln!(f, "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]").unwrap();
+    writeln!(f, "pub enum PgEncoding {{").unwrap();
+    for &(ref name, value) in &encodings {
+        writeln!(f, "    {} = {},", camel_case(name, ""), value).unwrap();
+    }
+    writeln!(f, "}}").unwrap();
+    writeln!(f).unwrap();
+
+    writeln!(f, "impl PgEncoding {{").unwrap();
+    writeln!(f, "    pub fn from_id(id: i32) -> Option<PgEncoding> {{").unwrap();
+    writeln!(f, "        match id {{").unwrap();
+    for &(ref name, value) in &encodings {
+        writeln!(f, "            {} => Some(PgEncoding::{}),", value, camel_case(name, "")).unwrap();
+    }
+    writeln!(f, "            _ => None,").unwrap();
+    writeln!(f, "        }}").unwrap();
+    writeln!(f, "    }}").unwrap();
+    writeln!(f).unwrap();
+
+    writeln!(f, "    // as in pg_encoding_to_char").unwrap();
+    writeln!(f, "    pub fn name(self) -> &'static str {{").unwrap();
+    writeln!(f, "        match self {{").unwrap();
+    for &(ref name, _) in &encodings {
+        writeln!(f, "            PgEncoding::{} => \"{}\",", camel_case(name, ""), name).unwrap();
+    }
+    writeln!(f, "        }}").unwrap();
+    writeln!(f, "    }}").unwrap();
+    writeln!(f).unwrap();
+
+    writeln!(f, "    // i.e. usable as a database encoding, compare PG_VALID_BE_ENCODING").unwrap();
+    writeln!(f, "    pub fn is_server_encoding(self) -> bool {{").unwrap();
+    writeln!(f, "        (self as i32) <= {}", be_last).unwrap();
+    writeln!(f, "    }}").unwrap();
+    writeln!(f, "}}").unwrap();
 }
@@ -3,11 +3,18 @@ extern crate cc;
 use std::process::Command;
 use std::str;
 
+fn pg_config(arg: &str) -> String {
+    let output = Command::new("pg_config").arg(arg).output().unwrap().stdout;
+    str::from_utf8(&output).unwrap().trim().to_owned()
+}
+
 fn main() {
-    let output = Command::new("pg_config").arg("--includedir-server").output().unwrap().stdout;
-    let includedir = str::from_utf8(&output).unwrap().trim();
+    let includedir = pg_config("--includedir-server");
     cc::Build::new()
         .file("src/gluedefs.c")
-        .include(includedir)
+        .include(&includedir)
         .compile("gluedefs");
+
+    println!("cargo:rustc-env=PG_SHAREDIR={}", pg_config("--sharedir"));
+    println!("cargo:rustc-env=PG_INCLUDEDIR_SERVER={}", includedir);
 }
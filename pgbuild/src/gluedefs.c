@@ -5,6 +5,8 @@
 #include <access/relscan.h>
 #include <utils/syscache.h>
 #include <utils/rel.h>
+#include <nodes/primnodes.h>
+#include <nodes/pg_list.h>
 #include <setjmp.h>
 #include <stddef.h>
 
@@ -16,9 +18,49 @@ uint32_t float4_byval() { return FLOAT4PASSBYVAL; }
 uint32_t float8_byval() { return FLOAT8PASSBYVAL; }
 uint32_t len_scankeydata() { return sizeof(ScanKeyData); }
 uint32_t len_sigjmpbuf() { return sizeof(sigjmp_buf); }
+uint32_t maximum_alignof() { return MAXIMUM_ALIGNOF; }
+uint32_t alignof_double() { return ALIGNOF_DOUBLE; }
+
+uint32_t elevel_debug5() { return DEBUG5; }
+uint32_t elevel_debug4() { return DEBUG4; }
+uint32_t elevel_debug3() { return DEBUG3; }
+uint32_t elevel_debug2() { return DEBUG2; }
+uint32_t elevel_debug1() { return DEBUG1; }
+uint32_t elevel_log() { return LOG; }
+#ifdef LOG_SERVER_ONLY
+uint32_t elevel_log_server_only() { return LOG_SERVER_ONLY; }
+#else
+uint32_t elevel_log_server_only() { return COMMERROR; }
+#endif
+uint32_t elevel_info() { return INFO; }
+uint32_t elevel_notice() { return NOTICE; }
+uint32_t elevel_warning() { return WARNING; }
+uint32_t elevel_error() { return ERROR; }
+uint32_t elevel_fatal() { return FATAL; }
+uint32_t elevel_panic() { return PANIC; }
 
 uint32_t cacheid_typeoid() { return TYPEOID; }
+uint32_t cacheid_enumoid() { return ENUMOID; }
+uint32_t cacheid_enumtypoidname() { return ENUMTYPOIDNAME; }
 
 uint32_t relatt_offset() { return offsetof(RelationData, rd_att); }
 uint32_t rs_cbuf_offset() { return offsetof(HeapScanDescData, rs_cbuf); }
 uint32_t xs_cbuf_offset() { return offsetof(IndexScanDescData, xs_cbuf); }
+uint32_t rd_opfamily_offset() { return offsetof(RelationData, rd_opfamily); }
+uint32_t rd_opcintype_offset() { return offsetof(RelationData, rd_opcintype); }
+uint32_t rd_index_offset() { return offsetof(RelationData, rd_index); }
+#if PG_VERSION_NUM >= 110000
+uint32_t indnkeyatts_offset() { return offsetof(FormData_pg_index, indnkeyatts); }
+#else
+uint32_t indnkeyatts_offset() { return offsetof(FormData_pg_index, indnatts); }
+#endif
+
+uint32_t t_funcexpr() { return T_FuncExpr; }
+uint32_t t_opexpr() { return T_OpExpr; }
+uint32_t funcexpr_args_offset() { return offsetof(FuncExpr, args); }
+uint32_t opexpr_args_offset() { return offsetof(OpExpr, args); }
+#if PG_VERSION_NUM >= 130000
+uint32_t list_elements_offset() { return offsetof(List, elements); }
+#else
+uint32_t list_elements_offset() { return offsetof(List, head); }
+#endif
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 extern "C" {
     pub fn pg_version() -> u32;
     pub fn func_max_args() -> u32;
@@ -7,9 +9,45 @@ extern "C" {
     pub fn float8_byval() -> u32;
     pub fn len_scankeydata() -> u32;
     pub fn len_sigjmpbuf() -> u32;
+    pub fn maximum_alignof() -> u32;
+    pub fn alignof_double() -> u32;
+
+    pub fn elevel_debug5() -> u32;
+    pub fn elevel_debug4() -> u32;
+    pub fn elevel_debug3() -> u32;
+    pub fn elevel_debug2() -> u32;
+    pub fn elevel_debug1() -> u32;
+    pub fn elevel_log() -> u32;
+    pub fn elevel_log_server_only() -> u32;
+    pub fn elevel_info() -> u32;
+    pub fn elevel_notice() -> u32;
+    pub fn elevel_warning() -> u32;
+    pub fn elevel_error() -> u32;
+    pub fn elevel_fatal() -> u32;
+    pub fn elevel_panic() -> u32;
 
     pub fn cacheid_typeoid() -> u32;
+    pub fn cacheid_enumoid() -> u32;
+    pub fn cacheid_enumtypoidname() -> u32;
     pub fn relatt_offset() -> u32;
     pub fn rs_cbuf_offset() -> u32;
     pub fn xs_cbuf_offset() -> u32;
+    pub fn rd_opfamily_offset() -> u32;
+    pub fn rd_opcintype_offset() -> u32;
+    pub fn rd_index_offset() -> u32;
+    pub fn indnkeyatts_offset() -> u32;
+
+    pub fn t_funcexpr() -> u32;
+    pub fn t_opexpr() -> u32;
+    pub fn funcexpr_args_offset() -> u32;
+    pub fn opexpr_args_offset() -> u32;
+    pub fn list_elements_offset() -> u32;
+}
+
+pub fn errcodes_txt() -> PathBuf {
+    PathBuf::from(env!("PG_SHAREDIR")).join("errcodes.txt")
+}
+
+pub fn pg_wchar_h() -> PathBuf {
+    PathBuf::from(env!("PG_INCLUDEDIR_SERVER")).join("mb").join("pg_wchar.h")
 }
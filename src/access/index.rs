@@ -27,8 +27,31 @@ extern "C" {
     fn index_endscan(scan: IndexScanDesc);
 
     fn ScanKeyInit(entry: *mut ScanKey, attr_num: u16, strat_num: u16, regproc: u32, arg: usize);
+    fn get_opfamily_member(opfamily: Oid, lefttype: Oid, righttype: Oid, strategy: i16) -> Oid;
+    fn get_opcode(opno: Oid) -> u32;
 }
 
+// btree strategy numbers, compare access/stratnum.h
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Less = 1,
+    LessEqual = 2,
+    Equal = 3,
+    GreaterEqual = 4,
+    Greater = 5,
+}
+
+// IndexRelationGetNumberOfKeyAttributes, i.e. rd_index->indnkeyatts (indnatts before 11)
+unsafe fn key_columns(index: *const Relation) -> u16 {
+    let form = *((index as *const u8).offset(::RD_INDEX_OFFSET as isize) as *const *const u8);
+    *(form.offset(::INDNKEYATTS_OFFSET as isize) as *const i16) as u16
+}
+
+// rd_opfamily[column - 1] and friends, column must be a key column
+unsafe fn column_oid(index: *const Relation, offset: usize, column: u16) -> Oid {
+    let oids = *((index as *const u8).offset(offset as isize) as *const *const Oid);
+    *oids.offset(column as isize - 1)
+}
 
 #[repr(C)]
 pub struct ScanKey {
@@ -36,13 +59,26 @@ pub struct ScanKey {
 }
 
 impl ScanKey {
+    // the operator comes from the index's operator family, so this works for any btree opclass,
+    // including the ones created by CREATE_OPERATORS! (value has to be of the opclass' input type)
     // TODO: value is a Datum
-    pub fn new(column: u16, value: usize) -> ScanKey {
+    pub fn new(index: &Index, column: u16, strategy: Strategy, value: usize) -> ScanKey {
+        let key_columns = unsafe { key_columns(index.0) };
+        assert!(column >= 1 && column <= key_columns,
+                "index column {} out of range, the index has {} key columns (numbered from 1)", column, key_columns);
         unsafe {
+            let opfamily = column_oid(index.0, ::RD_OPFAMILY_OFFSET, column);
+            let opcintype = column_oid(index.0, ::RD_OPCINTYPE_OFFSET, column);
+            let opcode = error::convert_postgres_error(|| {
+                let operator = get_opfamily_member(opfamily, opcintype, opcintype, strategy as i16);
+                if operator == Oid(0) { 0 } else { get_opcode(operator) }
+            });
+            if opcode == 0 {
+                panic!("operator family {} has no operator for strategy {:?}", opfamily.0, strategy);
+            }
             error::convert_postgres_error(|| {
-                let btint4cmp = 184;
                 let mut buf: ScanKey = mem::uninitialized();
-                ScanKeyInit(&mut buf, column, 3, btint4cmp, value);
+                ScanKeyInit(&mut buf, column, strategy as u16, opcode, value);
                 buf
             })
         }
@@ -51,7 +87,6 @@ impl ScanKey {
 
 // FIXME: this code works with btree index only.
 //        need to figure out how this /actually/ works
-// FIXME: we only support eq right now, but gt/lt should be easy to do hopefully?
 // FIXME: validate index structure (postgres does not guard against invalid scankeys,
 //        most importantly column id needs to be valid or things break horribly)
 
@@ -13,6 +13,7 @@ extern "C" {
     fn MemoryContextAllocZero(context: *mut c_void, size: usize) -> *mut c_void;
 
     fn MemoryContextDelete(context: *mut c_void);
+    fn MemoryContextReset(context: *mut c_void);
     static mut CurrentMemoryContext: *mut c_void;
 }
 
@@ -30,6 +31,14 @@ pub unsafe fn get_current_ctx() -> ManuallyDrop<MemoryContext<'static>> {
     })
 }
 
+// a context that belongs to postgres (or some other part of our code) and must not be deleted by us
+pub(crate) unsafe fn borrow_ctx(ptr: *mut c_void) -> ManuallyDrop<MemoryContext<'static>> {
+    ManuallyDrop::new(MemoryContext {
+        ptr,
+        parent: PhantomData,
+    })
+}
+
 impl<'parent> MemoryContext<'parent> {
     pub fn create_allocset(parent: Option<&'parent MemoryContext<'parent>>,
                            // name: cstr
@@ -55,6 +64,15 @@ impl<'parent> MemoryContext<'parent> {
         unsafe { MemoryContextAlloc(self.ptr, size) as *mut u8 }
     }
 
+    pub(crate) fn as_ptr(&self) -> *mut c_void {
+        self.ptr
+    }
+
+    // frees everything allocated in this context (and deletes its children)
+    pub fn reset(&mut self) {
+        unsafe { MemoryContextReset(self.ptr) }
+    }
+
     pub unsafe fn set_current(&self) {
         CurrentMemoryContext = self.ptr;
     }
@@ -0,0 +1,371 @@
+use std::os::raw::{c_void, c_char};
+use std::marker::PhantomData;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::{mem, slice};
+
+use Datum;
+use error;
+use alloc::MemoryContext;
+use types::{StaticallyTyped, FromDatum, Oid, bool, char, int2, int4, int8, float4, float8, oid, text, bytea};
+use varlena::{self, BaseVarlena, Varlena, Toasted};
+
+// compare utils/array.h for the on-disk format
+
+#[repr(C)]
+struct ArrayHeader {
+    vl_len_: i32,
+    ndim: i32,
+    dataoffset: i32, // 0 if no null bitmap
+    elemtype: Oid,
+}
+
+extern "C" {
+    fn get_typlenbyvalalign(typid: Oid, typlen: *mut i16, typbyval: *mut u8, typalign: *mut c_char);
+
+    fn construct_md_array(elems: *const Datum, nulls: *const u8, ndims: i32, dims: *const i32, lbs: *const i32,
+                          elmtype: Oid, elmlen: i32, elmbyval: u8, elmalign: c_char) -> *mut u8;
+    fn initArrayResult(element_type: Oid, rcontext: *mut c_void, subcontext: u8) -> *mut c_void;
+    fn accumArrayResult(astate: *mut c_void, dvalue: Datum, disnull: u8, element_type: Oid, rcontext: *mut c_void) -> *mut c_void;
+    fn makeMdArrayResult(astate: *mut c_void, ndims: i32, dims: *const i32, lbs: *const i32, rcontext: *mut c_void, release: u8) -> Datum<'static>;
+}
+
+// types that can be elements of an array, along with their array type
+pub unsafe trait ArrayElement: StaticallyTyped { const ARRAY_OID: Oid; }
+unsafe impl ArrayElement for bool { const ARRAY_OID: Oid = Oid(1000); }
+unsafe impl<'a> ArrayElement for Toasted<'a, bytea> { const ARRAY_OID: Oid = Oid(1001); }
+unsafe impl ArrayElement for char { const ARRAY_OID: Oid = Oid(1002); }
+unsafe impl ArrayElement for int2 { const ARRAY_OID: Oid = Oid(1005); }
+unsafe impl ArrayElement for int4 { const ARRAY_OID: Oid = Oid(1007); }
+unsafe impl<'a> ArrayElement for Toasted<'a, text> { const ARRAY_OID: Oid = Oid(1009); }
+unsafe impl ArrayElement for int8 { const ARRAY_OID: Oid = Oid(1016); }
+unsafe impl ArrayElement for float4 { const ARRAY_OID: Oid = Oid(1021); }
+unsafe impl ArrayElement for float8 { const ARRAY_OID: Oid = Oid(1022); }
+unsafe impl ArrayElement for oid { const ARRAY_OID: Oid = Oid(1028); }
+
+// elements that are stored exactly like their rust representation (byval and properly aligned)
+// arrays of these can be accessed as slices
+pub unsafe trait FixedWidthElement: ArrayElement + Copy {}
+unsafe impl FixedWidthElement for bool {}
+unsafe impl FixedWidthElement for char {}
+unsafe impl FixedWidthElement for int2 {}
+unsafe impl FixedWidthElement for int4 {}
+unsafe impl FixedWidthElement for int8 {}
+unsafe impl FixedWidthElement for float4 {}
+unsafe impl FixedWidthElement for float8 {}
+unsafe impl FixedWidthElement for oid {}
+
+#[derive(Debug, Clone, Copy)]
+struct ElemInfo {
+    len: i16,
+    byval: bool,
+    align: c_char,
+}
+
+impl ElemInfo {
+    fn lookup(typ: Oid) -> ElemInfo {
+        let (mut len, mut byval, mut align) = (0i16, 0u8, 0 as c_char);
+        let (len_ptr, byval_ptr, align_ptr) = (&mut len as *mut _, &mut byval as *mut _, &mut align as *mut _);
+        error::convert_postgres_error(|| unsafe { get_typlenbyvalalign(typ, len_ptr, byval_ptr, align_ptr) });
+        ElemInfo { len, byval: byval != 0, align }
+    }
+
+    // compare att_align_nominal
+    fn align(&self, offset: usize) -> usize {
+        let alignment = match self.align as u8 {
+            b'c' => 1,
+            b's' => 2,
+            b'i' => 4,
+            b'd' => ::ALIGNOF_DOUBLE,
+            x => panic!("Unknown typalign {}", x),
+        };
+        (offset + alignment - 1) & !(alignment - 1)
+    }
+
+    // compare fetch_att and att_addlength_pointer
+    unsafe fn fetch<'a>(&self, ptr: *const u8) -> (Datum<'a>, usize) {
+        if self.byval {
+            let value = match self.len {
+                1 => *(ptr as *const i8) as usize,
+                2 => *(ptr as *const i16) as usize,
+                4 => *(ptr as *const i32) as usize,
+                8 => *(ptr as *const usize),
+                x => panic!("Unsupported byval length {}", x),
+            };
+            (Datum::create(value), self.len as usize)
+        } else {
+            let len = match self.len {
+                -1 => varlena::varsize_any(ptr),
+                -2 => ::std::ffi::CStr::from_ptr(ptr as *const c_char).to_bytes_with_nul().len(),
+                x => x as usize,
+            };
+            (Datum::create(ptr as usize), len)
+        }
+    }
+}
+
+// the element type is checked by whoever produces the reference (typecheck for function arguments)
+pub struct Array<T> {
+    marker: PhantomData<T>,
+    _inner: BaseVarlena,
+}
+
+unsafe impl<T> Varlena for Array<T> {
+    const ALIGNED: bool = true;
+
+    unsafe fn dst_ptrcast<'a, P>(ptr: *const P) -> &'a mut Self {
+        &mut *(slice::from_raw_parts_mut(ptr as *const u8 as *mut u8, 0) as *mut [u8] as *mut Array<T>)
+    }
+}
+
+unsafe impl<T: ArrayElement> StaticallyTyped for Array<T> { const OID: Oid = T::ARRAY_OID; }
+
+impl<T> Array<T> {
+    fn header(&self) -> &ArrayHeader {
+        unsafe { &*(self as *const _ as *const ArrayHeader) }
+    }
+
+    fn base_ptr(&self) -> *const u8 {
+        self as *const _ as *const u8
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.header().ndim as usize
+    }
+
+    pub fn elemtype(&self) -> Oid {
+        self.header().elemtype
+    }
+
+    pub fn dims(&self) -> &[i32] {
+        unsafe {
+            let ptr = self.base_ptr().offset(mem::size_of::<ArrayHeader>() as isize) as *const i32;
+            slice::from_raw_parts(ptr, self.ndim())
+        }
+    }
+
+    pub fn lower_bounds(&self) -> &[i32] {
+        unsafe {
+            let ptr = self.base_ptr().offset(mem::size_of::<ArrayHeader>() as isize) as *const i32;
+            slice::from_raw_parts(ptr.offset(self.ndim() as isize), self.ndim())
+        }
+    }
+
+    // total number of elements (across all dimensions)
+    pub fn len(&self) -> usize {
+        if self.ndim() == 0 {
+            0
+        } else {
+            self.dims().iter().map(|&d| d as usize).product()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn null_bitmap(&self) -> Option<&[u8]> {
+        if self.header().dataoffset == 0 {
+            None
+        } else {
+            unsafe {
+                let offset = mem::size_of::<ArrayHeader>() + 2 * mem::size_of::<i32>() * self.ndim();
+                Some(slice::from_raw_parts(self.base_ptr().offset(offset as isize), (self.len() + 7) / 8))
+            }
+        }
+    }
+
+    pub fn has_nulls(&self) -> bool {
+        let len = self.len();
+        match self.null_bitmap() {
+            None => false,
+            Some(bitmap) => (0..len).any(|i| bitmap[i / 8] & (1 << (i % 8)) == 0),
+        }
+    }
+
+    // compare ARR_DATA_PTR
+    fn data_ptr(&self) -> *const u8 {
+        let offset = match self.header().dataoffset {
+            0 => {
+                let overhead = mem::size_of::<ArrayHeader>() + 2 * mem::size_of::<i32>() * self.ndim();
+                (overhead + ::MAXIMUM_ALIGNOF - 1) & !(::MAXIMUM_ALIGNOF - 1)
+            }
+            x => x as usize,
+        };
+        unsafe { self.base_ptr().offset(offset as isize) }
+    }
+
+    // elements in storage order (i.e. the last dimension varies fastest)
+    pub fn iter<'a>(&'a self) -> ArrayIter<'a, T> where T: FromDatum<'a> {
+        ArrayIter {
+            info: ElemInfo::lookup(self.elemtype()),
+            bitmap: self.null_bitmap(),
+            data: self.data_ptr(),
+            offset: 0,
+            index: 0,
+            len: self.len(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: FixedWidthElement> Array<T> {
+    // zero-copy access, None if there are nulls
+    pub fn as_slice(&self) -> Option<&[T]> {
+        if self.has_nulls() {
+            None
+        } else {
+            unsafe { Some(slice::from_raw_parts(self.data_ptr() as *const T, self.len())) }
+        }
+    }
+}
+
+impl<'a, T: ArrayElement + Into<Datum<'a>>> Array<T> {
+    // compare construct_md_array
+    pub fn construct_md<'b, 'c>(alloc: &'b MemoryContext<'c>,
+                                elems: Vec<Option<T>>,
+                                dims: &[i32],
+                                lower_bounds: &[i32]) -> &'b Array<T> {
+        assert_eq!(dims.len(), lower_bounds.len());
+        assert_eq!(dims.iter().map(|&d| d as usize).product::<usize>(), elems.len());
+
+        let info = ElemInfo::lookup(T::OID);
+        let nulls: Vec<u8> = elems.iter().map(|e| e.is_none() as u8).collect();
+        let values: Vec<Datum> = elems.into_iter().map(|e| e.map(Into::into).unwrap_or(Datum::create(0))).collect();
+
+        unsafe {
+            alloc.set_current();
+            let ptr = error::convert_postgres_error(|| {
+                construct_md_array(values.as_ptr(), nulls.as_ptr(), dims.len() as i32, dims.as_ptr(), lower_bounds.as_ptr(),
+                                   T::OID, info.len as i32, info.byval as u8, info.align)
+            });
+            Array::dst_ptrcast(ptr)
+        }
+    }
+
+    pub fn construct<'b, 'c>(alloc: &'b MemoryContext<'c>, elems: Vec<Option<T>>) -> &'b Array<T> {
+        let dims = [elems.len() as i32];
+        Array::construct_md(alloc, elems, &dims, &[1])
+    }
+}
+
+impl<T> Debug for Array<T> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "ARRAY{:?}[", self.dims())?;
+        // we don't know whether T can be built from any lifetime, so just print the raw datums
+        let iter: ArrayIter<Datum> = ArrayIter {
+            info: ElemInfo::lookup(self.elemtype()),
+            bitmap: self.null_bitmap(),
+            data: self.data_ptr(),
+            offset: 0,
+            index: 0,
+            len: self.len(),
+            marker: PhantomData,
+        };
+        for elem in iter {
+            write!(fmt, "{:?}, ", elem)?;
+        }
+        write!(fmt, "]")
+    }
+}
+
+pub struct ArrayIter<'a, T> {
+    info: ElemInfo,
+    bitmap: Option<&'a [u8]>,
+    data: *const u8,
+    offset: usize,
+    index: usize,
+    len: usize,
+    marker: PhantomData<(&'a (), T)>,
+}
+
+impl<'a, T: FromDatum<'a>> Iterator for ArrayIter<'a, T> {
+    type Item = Option<T>;
+
+    fn next(&mut self) -> Option<Option<T>> {
+        if self.index >= self.len {
+            return None;
+        }
+
+        let i = self.index;
+        self.index += 1;
+
+        if let Some(bitmap) = self.bitmap {
+            if bitmap[i / 8] & (1 << (i % 8)) == 0 {
+                // nulls take up no space in the data area
+                return Some(None);
+            }
+        }
+
+        unsafe {
+            let (datum, len) = self.info.fetch(self.data.offset(self.offset as isize));
+            self.offset = self.info.align(self.offset + len);
+            Some(Some(T::from(datum)))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.len - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T: FromDatum<'a>> ExactSizeIterator for ArrayIter<'a, T> {}
+
+// builds a new array element by element (compare accumArrayResult)
+pub struct ArrayBuilder<'b, T> {
+    state: *mut c_void,
+    alloc: &'b MemoryContext<'b>,
+    count: usize,
+    marker: PhantomData<T>,
+}
+
+impl<'a, 'b, T: ArrayElement + Into<Datum<'a>>> ArrayBuilder<'b, T> {
+    pub fn new(alloc: &'b MemoryContext<'b>) -> ArrayBuilder<'b, T> {
+        let state = error::convert_postgres_error(|| unsafe { initArrayResult(T::OID, alloc.as_ptr(), 1) });
+        ArrayBuilder {
+            state,
+            alloc,
+            count: 0,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn push(&mut self, value: Option<T>) {
+        let (datum, isnull) = match value {
+            Some(v) => (v.into(), 0),
+            None => (Datum::create(0), 1),
+        };
+
+        let state = self.state;
+        let alloc = self.alloc.as_ptr();
+        self.state = error::convert_postgres_error(|| unsafe { accumArrayResult(state, datum, isnull, T::OID, alloc) });
+        self.count += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn finish(self) -> &'b Array<T> {
+        let dims = [self.count as i32];
+        self.finish_md(&dims, &[1])
+    }
+
+    // the elements are laid out in storage order (i.e. the last dimension varies fastest)
+    pub fn finish_md(self, dims: &[i32], lower_bounds: &[i32]) -> &'b Array<T> {
+        assert_eq!(dims.len(), lower_bounds.len());
+        assert_eq!(dims.iter().map(|&d| d as usize).product::<usize>(), self.count);
+
+        // an empty array has no dimensions at all
+        let ndims = if self.count == 0 { 0 } else { dims.len() as i32 };
+        let (state, alloc) = (self.state, self.alloc.as_ptr());
+        unsafe {
+            let datum = error::convert_postgres_error(|| makeMdArrayResult(state, ndims, dims.as_ptr(), lower_bounds.as_ptr(), alloc, 1));
+            Array::dst_ptrcast(datum.0 as *const u8)
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::os::raw::{c_char, c_void};
+use std::ffi::CStr;
+use std::{ptr, slice};
+
+use Datum;
+use varlena::{self, Varlena, Toasted};
+use types::{FromDatum, bytea};
+use alloc::MemoryContext;
+use export::FunctionCallContext;
+use sqlstate::SqlState;
+use stringinfo;
+use encoding;
+use error;
+
+// compare CREATE TYPE: a new base type whose values are a Rust type
+// the values are stored as a varlena, the payload is whatever to_payload produces
+
+extern "C" {
+    fn ArrayGetIntegerTypmods(arr: *const c_void, n: *mut i32) -> *const i32;
+}
+
+pub trait BaseType: Sized {
+    // whether receive and send are implemented and should be declared
+    const BINARY_IO: bool = false;
+    // whether the type takes a modifier, e.g. rbitset(64)
+    const TYPMOD: bool = false;
+
+    // the varlena payload, which may be unaligned
+    fn from_payload(payload: &[u8]) -> Self;
+    fn to_payload(&self) -> Vec<u8>;
+
+    // typmod is -1 if there is none
+    // raise e.g. InvalidTextRepresentation for malformed input
+    fn input(s: &str, typmod: i32) -> Self;
+    fn output(&self) -> String;
+
+    // the binary format, which unlike the payload must not depend on the platform
+    fn receive(_data: &[u8], _typmod: i32) -> Self {
+        error::raise(SqlState::FeatureNotSupported, "no binary input function available")
+    }
+    fn send(&self) -> Vec<u8> {
+        error::raise(SqlState::FeatureNotSupported, "no binary output function available")
+    }
+
+    // the integers given in parentheses
+    fn typmod_in(_mods: &[i32]) -> i32 {
+        error::raise(SqlState::SyntaxError, "type modifier is not allowed for this type")
+    }
+    fn typmod_out(typmod: i32) -> String {
+        format!("({})", typmod)
+    }
+}
+
+// copies the value into a new varlena
+pub fn store<'b, 'c, T: BaseType, V: Varlena + ?Sized>(value: &T, alloc: &'b MemoryContext<'c>) -> &'b V {
+    let payload = value.to_payload();
+    let size = 4 + payload.len();
+    unsafe {
+        let buf = alloc.alloc_undef(size);
+        ptr::copy_nonoverlapping(payload.as_ptr(), buf.offset(4), payload.len());
+        varlena::set_varsize(buf, size);
+        V::dst_ptrcast(buf)
+    }
+}
+
+// the value must have been detoasted (packed is fine)
+pub fn load<T: BaseType, V: Varlena + ?Sized>(value: &V) -> T {
+    unsafe { T::from_payload(varlena::payload(value as *const V as *const u8)) }
+}
+
+// a palloc'd cstring in the database encoding
+fn alloc_cstring<'b, 'c>(value: &str, alloc: &'b MemoryContext<'c>) -> *const c_char {
+    assert!(!value.contains('\0'), "output contains a nul byte");
+    encoding::with_server_encoding(value, |bytes| {
+        let buf = alloc.alloc(bytes.len() + 1); // zeroed, so it's already terminated
+        buf[..bytes.len()].copy_from_slice(bytes);
+        buf.as_ptr() as *const c_char
+    })
+}
+
+// (cstring, oid, integer) and (internal, oid, integer), the typmod is missing in some callers
+fn io_typmod(args: &[Datum]) -> i32 {
+    args.get(2).map(|d| d.0 as i32).unwrap_or(-1)
+}
+
+#[doc(hidden)]
+pub fn call_input<'a, T: BaseType, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let args = ctx.fcinfo().args_strict();
+    let s = unsafe { CStr::from_ptr(args[0].0 as *const c_char) };
+    let s = encoding::server_to_utf8(s.to_bytes()).unwrap_or_else(|e| e.raise());
+    let value = T::input(&s, io_typmod(args));
+    let ret: Toasted<V> = store::<T, V>(&value, ctx.result_allocator()).into();
+    ret.into()
+}
+
+#[doc(hidden)]
+pub fn call_output<'a, T: BaseType, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let arg: Toasted<V> = unsafe { FromDatum::from(ctx.fcinfo().args_strict()[0]) };
+    let value = load::<T, V>(arg.detoast_packed(ctx.allocator()));
+    Datum::create(alloc_cstring(&value.output(), ctx.result_allocator()) as usize)
+}
+
+#[doc(hidden)]
+pub fn call_receive<'a, T: BaseType, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let args = ctx.fcinfo().args_strict();
+    let data = unsafe { stringinfo::take_remaining(args[0].0 as *mut c_void) };
+    let value = T::receive(data, io_typmod(args));
+    let ret: Toasted<V> = store::<T, V>(&value, ctx.result_allocator()).into();
+    ret.into()
+}
+
+#[doc(hidden)]
+pub fn call_send<'a, T: BaseType, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let arg: Toasted<V> = unsafe { FromDatum::from(ctx.fcinfo().args_strict()[0]) };
+    let data = load::<T, V>(arg.detoast_packed(ctx.allocator())).send();
+    let ret = ctx.alloc_bytea(data.len());
+    ret.copy_from_slice(&data);
+    let ret: Toasted<bytea> = (&*ret).into();
+    ret.into()
+}
+
+#[doc(hidden)]
+pub fn call_typmod_in<'a, T: BaseType>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let arr = ctx.fcinfo().args_strict()[0].0 as *const c_void;
+    let mut n = 0i32;
+    let n_ptr = &mut n as *mut i32;
+    let mods = error::convert_postgres_error(|| unsafe { ArrayGetIntegerTypmods(arr, n_ptr) });
+    let mods = unsafe { slice::from_raw_parts(mods, n as usize) };
+    Datum::create(T::typmod_in(mods) as usize)
+}
+
+#[doc(hidden)]
+pub fn call_typmod_out<'a, T: BaseType>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let typmod = ctx.fcinfo().args_strict()[0].0 as i32;
+    Datum::create(alloc_cstring(&T::typmod_out(typmod), ctx.result_allocator()) as usize)
+}
+
+// the C symbols exported by CREATE_BASE_TYPE!
+#[doc(hidden)]
+pub struct SupportFunctions {
+    pub input: &'static str,
+    pub output: &'static str,
+    pub receive: &'static str,
+    pub send: &'static str,
+    pub typmod_in: &'static str,
+    pub typmod_out: &'static str,
+}
+
+// for an extension script, hence MODULE_PATHNAME
+pub(crate) fn create_function_sql(fname: &str, args: &str, ret: &str) -> String {
+    // PARALLEL is 9.6+
+    let parallel = if ::PG_VERSION >= 906 { " PARALLEL SAFE" } else { "" };
+    format!("CREATE FUNCTION {}({}) RETURNS {} AS 'MODULE_PATHNAME', '{}' LANGUAGE C IMMUTABLE STRICT{};\n",
+            fname, args, ret, fname, parallel)
+}
+
+#[doc(hidden)]
+pub fn create_type_sql<T: BaseType>(name: &str, f: &SupportFunctions) -> String {
+    // the functions can only be created once the shell type exists
+    let mut sql = format!("CREATE TYPE {};\n", name);
+    let mut options = format!("INPUT = {}, OUTPUT = {}", f.input, f.output);
+    sql += &create_function_sql(f.input, "cstring, oid, integer", name);
+    sql += &create_function_sql(f.output, name, "cstring");
+    if T::BINARY_IO {
+        sql += &create_function_sql(f.receive, "internal, oid, integer", name);
+        sql += &create_function_sql(f.send, name, "bytea");
+        options += &format!(", RECEIVE = {}, SEND = {}", f.receive, f.send);
+    }
+    if T::TYPMOD {
+        sql += &create_function_sql(f.typmod_in, "cstring[]", "integer");
+        sql += &create_function_sql(f.typmod_out, "integer", "cstring");
+        options += &format!(", TYPMOD_IN = {}, TYPMOD_OUT = {}", f.typmod_in, f.typmod_out);
+    }
+    sql += &format!("CREATE TYPE {} ({}, INTERNALLENGTH = VARIABLE, STORAGE = extended);\n", name, options);
+    sql
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! basetype_export {
+    ( $fname:ident @ $finfo:ident => $call:expr ) => {
+        #[no_mangle]
+        pub extern "C" fn $finfo () -> *const $crate::export::Pg_finfo_record {
+            static FINFO: $crate::export::Pg_finfo_record = $crate::export::Pg_finfo_record { version: 1 };
+            &FINFO
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn $fname (fcinfo: $crate::export::FunctionCallInfo) -> $crate::Datum {
+            let ctx = fcinfo.bootstrap();
+            $crate::error::convert_rust_panic(|| $call(&ctx))
+        }
+    }
+}
+
+// declares the varlena type $name holding values of $rust, exports the support functions
+// and adds $name::create_sql() for the extension script
+#[macro_export]
+macro_rules! CREATE_BASE_TYPE {
+    ( type $name:ident = $rust:ty {
+        input: $input:ident @ $input_finfo:ident,
+        output: $output:ident @ $output_finfo:ident,
+        receive: $receive:ident @ $receive_finfo:ident,
+        send: $send:ident @ $send_finfo:ident,
+        typmod_in: $typmod_in:ident @ $typmod_in_finfo:ident,
+        typmod_out: $typmod_out:ident @ $typmod_out_finfo:ident $(,)*
+    } ) => {
+        pub struct $name($crate::varlena::BaseVarlena);
+        impl_varlena!($name);
+
+        impl $name {
+            pub fn new<'b, 'c>(value: &$rust, alloc: &'b $crate::alloc::MemoryContext<'c>) -> &'b $name {
+                $crate::basetype::store::<$rust, $name>(value, alloc)
+            }
+
+            pub fn get(&self) -> $rust {
+                $crate::basetype::load::<$rust, $name>(self)
+            }
+
+            pub fn create_sql() -> String {
+                $crate::basetype::create_type_sql::<$rust>(stringify!($name), &$crate::basetype::SupportFunctions {
+                    input: stringify!($input),
+                    output: stringify!($output),
+                    receive: stringify!($receive),
+                    send: stringify!($send),
+                    typmod_in: stringify!($typmod_in),
+                    typmod_out: stringify!($typmod_out),
+                })
+            }
+        }
+
+        basetype_export!($input @ $input_finfo => $crate::basetype::call_input::<$rust, $name>);
+        basetype_export!($output @ $output_finfo => $crate::basetype::call_output::<$rust, $name>);
+        basetype_export!($receive @ $receive_finfo => $crate::basetype::call_receive::<$rust, $name>);
+        basetype_export!($send @ $send_finfo => $crate::basetype::call_send::<$rust, $name>);
+        basetype_export!($typmod_in @ $typmod_in_finfo => $crate::basetype::call_typmod_in::<$rust>);
+        basetype_export!($typmod_out @ $typmod_out_finfo => $crate::basetype::call_typmod_out::<$rust>);
+    }
+}
@@ -1,6 +1,8 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use Datum;
 use types::{name, Oid};
+use encoding;
+use error;
 
 // TODO: pg10 has exported SearchSysCache1, but 9.5 does not
 
@@ -12,6 +14,8 @@ extern HeapTuple SearchSysCache(int cacheId,
 #[repr(i32)]
 enum SysCacheId {
     Type = ::CACHEID_TYPEOID,
+    Enum = ::CACHEID_ENUMOID,
+    EnumTypOidName = ::CACHEID_ENUMTYPOIDNAME,
 }
 
 //type HeapTuple = *const c_void;
@@ -40,39 +44,91 @@ struct HeapTuple {
 extern {
     fn SearchSysCache(cacheid: SysCacheId, key1: Datum, key2: Datum, key3: Datum, key4: Datum) -> *const HeapTuple;
     fn ReleaseSysCache(tuple: *const HeapTuple);
-}
 
-struct pg_type {
-    typname: name,
-    // ...
+    fn getBaseType(typid: Oid) -> Oid;
+    fn type_is_enum(typid: Oid) -> u8;
 }
 
-pub struct Type {
-    ptr: *const HeapTuple,
-}
+// a tuple from the syscache, released when dropped
+struct CacheTuple(*const HeapTuple);
 
-impl Type {
-    pub fn new(oid: Oid) -> Option<Type> {
+impl CacheTuple {
+    fn search(cache: SysCacheId, key1: Datum, key2: Datum) -> Option<CacheTuple> {
         unsafe {
             let z = Datum::from(0);
-            let tup = SearchSysCache(SysCacheId::Type, oid.into(), z, z, z);
-            if tup.is_null() {
-                None
+            let tup = error::convert_postgres_error(|| SearchSysCache(cache, key1, key2, z, z));
+            if tup.is_null() { None } else { Some(CacheTuple(tup)) }
+        }
+    }
+
+    // GETSTRUCT
+    unsafe fn data(&self) -> *const u8 {
+        let hdr = (*self.0).t_data;
+        (hdr as *const u8).offset((*hdr).t_hoff as isize)
+    }
+
+    // since 12 the oid is the first column, before that it was stored in the header (HeapTupleGetOid)
+    fn oid(&self) -> Oid {
+        unsafe {
+            if ::PG_VERSION >= 1200 {
+                *(self.data() as *const Oid)
             } else {
-                Some(Type { ptr: tup })
+                *(self.data().offset(-4) as *const Oid)
             }
         }
     }
+}
 
-    unsafe fn getstruct(&self) -> *const pg_type {
-        let hdr = (*self.ptr).t_data;
-        ((hdr as usize) + ((*hdr).t_hoff as usize)) as *const pg_type
+impl Drop for CacheTuple {
+    fn drop(&mut self) {
+        unsafe {
+            ReleaseSysCache(self.0);
+        }
+    }
+}
+
+// without the oid column, see CacheTuple::oid
+#[repr(C)]
+struct pg_type {
+    typname: name,
+    // ...
+}
+
+// without the oid column, see CacheTuple::oid
+#[repr(C)]
+struct pg_enum {
+    #[allow(dead_code)]
+    enumtypid: Oid,
+    #[allow(dead_code)]
+    enumsortorder: f32,
+    enumlabel: name,
+}
+
+impl CacheTuple {
+    // skips the oid column, if there is one
+    unsafe fn columns(&self) -> *const u8 {
+        let offset = if ::PG_VERSION >= 1200 { 4 } else { 0 };
+        self.data().offset(offset)
+    }
+
+    fn pg_type(&self) -> &pg_type {
+        unsafe { &*(self.columns() as *const pg_type) }
+    }
+
+    fn pg_enum(&self) -> &pg_enum {
+        unsafe { &*(self.columns() as *const pg_enum) }
+    }
+}
+
+pub struct Type(CacheTuple);
+
+impl Type {
+    pub fn new(oid: Oid) -> Option<Type> {
+        CacheTuple::search(SysCacheId::Type, oid.into(), Datum::from(0)).map(Type)
     }
 
     pub fn name(&self) -> &CStr {
-        unsafe {
-            CStr::from_ptr((*self.getstruct()).typname.as_ptr())
-        }
+        self.0.pg_type().typname.as_cstr()
     }
 }
 
@@ -82,10 +138,28 @@ pub fn get_type_name(oid: Oid) -> Option<String> {
     Some(typ.name().to_string_lossy().into_owned())
 }
 
-impl Drop for Type {
-    fn drop(&mut self) {
-        unsafe {
-            ReleaseSysCache(self.ptr);
-        }
-    }
+// the label of an enum value (which is the oid of its pg_enum row), None if there is no such value
+pub fn enum_label(value: Oid) -> Option<String> {
+    let tup = CacheTuple::search(SysCacheId::Enum, value.into(), Datum::from(0))?;
+    let label = tup.pg_enum().enumlabel.as_cstr().to_bytes();
+    Some(encoding::server_to_utf8(label).unwrap_or_else(|e| e.raise()).into_owned())
+}
+
+// compare enum_in, None if the enum type has no such label
+pub fn enum_value(enum_type: Oid, label: &str) -> Option<Oid> {
+    // too long or containing nul can't be a label, enum_in would raise instead
+    let label = encoding::with_server_encoding(label, |bytes| {
+        if bytes.len() < ::NAMEDATALEN { CString::new(bytes).ok() } else { None }
+    })?;
+    let tup = CacheTuple::search(SysCacheId::EnumTypOidName, enum_type.into(), Datum::create(label.as_ptr() as usize))?;
+    Some(tup.oid())
+}
+
+// domains resolve to the type they're based on, anything else to itself
+pub fn base_type(typ: Oid) -> Oid {
+    error::convert_postgres_error(|| unsafe { getBaseType(typ) })
+}
+
+pub fn is_enum(typ: Oid) -> bool {
+    error::convert_postgres_error(|| unsafe { type_is_enum(typ) }) != 0
 }
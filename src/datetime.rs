@@ -0,0 +1,307 @@
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+
+use Datum;
+use types::{FromDatum, StaticallyTyped, Oid, palloc_datum, int64_from_datum, int64_to_datum};
+
+// compare utils/date.h, datatype/timestamp.h
+//
+// postgres counts from 2000-01-01 (in microseconds or days),
+// the min/max values of the underlying integers are reserved for -infinity/infinity
+
+const USECS_PER_DAY: i64 = 86_400_000_000;
+// POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE
+const UNIX_EPOCH_DAYS: i64 = 10_957;
+const UNIX_EPOCH_USECS: i64 = UNIX_EPOCH_DAYS * USECS_PER_DAY;
+
+// the supported range, compare IS_VALID_DATE and IS_VALID_TIMESTAMP
+// DATETIME_MIN_JULIAN - POSTGRES_EPOCH_JDATE and DATE_END_JULIAN - POSTGRES_EPOCH_JDATE (exclusive)
+const MIN_DATE: i64 = -2_451_545;
+const END_DATE: i64 = 2_145_031_949;
+// MIN_TIMESTAMP and END_TIMESTAMP (exclusive)
+const MIN_TIMESTAMP: i64 = -211_813_488_000_000_000;
+const END_TIMESTAMP: i64 = 9_223_371_331_200_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date(i32);
+
+impl Date {
+    pub const NEG_INFINITY: Date = Date(i32::min_value());
+    pub const INFINITY: Date = Date(i32::max_value());
+
+    // days since 2000-01-01
+    pub fn from_pg_days(days: i32) -> Date { Date(days) }
+    pub fn pg_days(self) -> i32 { self.0 }
+
+    pub fn is_infinite(self) -> bool {
+        self == Date::NEG_INFINITY || self == Date::INFINITY
+    }
+
+    // None outside the range postgres supports (4713 BC to 5874897 AD)
+    pub fn from_unix_days(days: i64) -> Option<Date> {
+        let pg = days.checked_sub(UNIX_EPOCH_DAYS)?;
+        if pg >= MIN_DATE && pg < END_DATE {
+            Some(Date(pg as i32))
+        } else {
+            None
+        }
+    }
+
+    // None for infinity
+    pub fn to_unix_days(self) -> Option<i64> {
+        if self.is_infinite() { None } else { Some(self.0 as i64 + UNIX_EPOCH_DAYS) }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Time(i64);
+
+impl Time {
+    // microseconds since midnight
+    pub fn from_micros(micros: i64) -> Option<Time> {
+        // 24:00:00 is a valid time in postgres
+        if micros >= 0 && micros <= USECS_PER_DAY { Some(Time(micros)) } else { None }
+    }
+    pub fn micros(self) -> i64 { self.0 }
+}
+
+// zone is in seconds *west* of UTC (i.e. the opposite of the usual convention)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeTz {
+    pub time: Time,
+    pub zone: i32,
+}
+
+macro_rules! timestamp_type {
+    ($name:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(i64);
+
+        impl $name {
+            pub const NEG_INFINITY: $name = $name(i64::min_value());
+            pub const INFINITY: $name = $name(i64::max_value());
+
+            // microseconds since 2000-01-01 00:00:00
+            pub fn from_pg_micros(micros: i64) -> $name { $name(micros) }
+            pub fn pg_micros(self) -> i64 { self.0 }
+
+            pub fn is_infinite(self) -> bool {
+                self == $name::NEG_INFINITY || self == $name::INFINITY
+            }
+
+            // None outside the range postgres supports (4713 BC to 294276 AD)
+            pub fn from_unix_micros(micros: i64) -> Option<$name> {
+                let pg = micros.checked_sub(UNIX_EPOCH_USECS)?;
+                if pg >= MIN_TIMESTAMP && pg < END_TIMESTAMP { Some($name(pg)) } else { None }
+            }
+
+            // None for infinity
+            pub fn to_unix_micros(self) -> Option<i64> {
+                if self.is_infinite() { None } else { self.0.checked_add(UNIX_EPOCH_USECS) }
+            }
+
+            pub fn from_system_time(t: SystemTime) -> Option<$name> {
+                let micros = match t.duration_since(UNIX_EPOCH) {
+                    Ok(d) => d.as_micros() as i128,
+                    Err(e) => -(e.duration().as_micros() as i128),
+                };
+                if micros < i64::min_value() as i128 || micros > i64::max_value() as i128 {
+                    return None;
+                }
+                $name::from_unix_micros(micros as i64)
+            }
+
+            pub fn to_system_time(self) -> Option<SystemTime> {
+                let micros = self.to_unix_micros()?;
+                if micros >= 0 {
+                    UNIX_EPOCH.checked_add(Duration::from_micros(micros as u64))
+                } else {
+                    UNIX_EPOCH.checked_sub(Duration::from_micros(micros.wrapping_neg() as u64))
+                }
+            }
+        }
+
+        impl<'a> From<$name> for Datum<'a> { fn from(t: $name) -> Datum<'a> { int64_to_datum(t.0) } }
+        impl<'a> FromDatum<'a> for $name { unsafe fn from(d: Datum<'a>) -> $name { $name(int64_from_datum(d)) } }
+    }
+}
+
+// a timestamp without time zone, i.e. a local date and time that could be anywhere
+timestamp_type!(Timestamp);
+// a point in time, stored as UTC
+timestamp_type!(TimestampTz);
+
+// months and days are kept separately because their length varies
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Interval {
+    pub micros: i64,
+    pub days: i32,
+    pub months: i32,
+}
+
+impl Interval {
+    // None if the interval has a month component (which has no fixed length)
+    pub fn to_micros(self) -> Option<i64> {
+        if self.months != 0 {
+            None
+        } else {
+            (self.days as i64).checked_mul(USECS_PER_DAY)?.checked_add(self.micros)
+        }
+    }
+}
+
+impl<'a> From<Date> for Datum<'a> { fn from(d: Date) -> Datum<'a> { Datum::create(d.0 as usize) } }
+impl<'a> FromDatum<'a> for Date { unsafe fn from(d: Datum<'a>) -> Date { Date(d.0 as i32) } }
+impl<'a> From<Time> for Datum<'a> { fn from(t: Time) -> Datum<'a> { int64_to_datum(t.0) } }
+impl<'a> FromDatum<'a> for Time { unsafe fn from(d: Datum<'a>) -> Time { Time(int64_from_datum(d)) } }
+
+// the byref ones are copied out (and into a palloc'd chunk in the current memory context)
+impl<'a> From<TimeTz> for Datum<'a> { fn from(t: TimeTz) -> Datum<'a> { unsafe { palloc_datum(t) } } }
+impl<'a> FromDatum<'a> for TimeTz { unsafe fn from(d: Datum<'a>) -> TimeTz { *(d.0 as *const TimeTz) } }
+impl<'a> From<Interval> for Datum<'a> { fn from(i: Interval) -> Datum<'a> { unsafe { palloc_datum(i) } } }
+impl<'a> FromDatum<'a> for Interval { unsafe fn from(d: Datum<'a>) -> Interval { *(d.0 as *const Interval) } }
+
+unsafe impl StaticallyTyped for Date { const OID: Oid = Oid(1082); }
+unsafe impl StaticallyTyped for Time { const OID: Oid = Oid(1083); }
+unsafe impl StaticallyTyped for Timestamp { const OID: Oid = Oid(1114); }
+unsafe impl StaticallyTyped for TimestampTz { const OID: Oid = Oid(1184); }
+unsafe impl StaticallyTyped for Interval { const OID: Oid = Oid(1186); }
+unsafe impl StaticallyTyped for TimeTz { const OID: Oid = Oid(1266); }
+
+
+#[cfg(feature = "chrono")]
+mod chrono_impls {
+    extern crate chrono;
+
+    use std::convert::TryFrom;
+    use self::chrono::{NaiveDate, NaiveTime, NaiveDateTime, DateTime, Utc, Timelike};
+    use super::*;
+
+    fn unix_epoch() -> NaiveDate { NaiveDate::from_ymd_opt(1970, 1, 1).unwrap() }
+
+    impl TryFrom<NaiveDate> for Date {
+        type Error = ();
+        fn try_from(d: NaiveDate) -> Result<Date, ()> {
+            Date::from_unix_days(d.signed_duration_since(unix_epoch()).num_days()).ok_or(())
+        }
+    }
+    impl TryFrom<Date> for NaiveDate {
+        type Error = ();
+        fn try_from(d: Date) -> Result<NaiveDate, ()> {
+            let days = d.to_unix_days().ok_or(())?;
+            unix_epoch().checked_add_signed(chrono::Duration::days(days)).ok_or(())
+        }
+    }
+
+    impl From<NaiveTime> for Time {
+        fn from(t: NaiveTime) -> Time {
+            // leap seconds are folded into the preceding second
+            let nanos = ::std::cmp::min(t.nanosecond(), 999_999_999);
+            Time(t.num_seconds_from_midnight() as i64 * 1_000_000 + (nanos / 1000) as i64)
+        }
+    }
+    impl TryFrom<Time> for NaiveTime {
+        type Error = ();
+        fn try_from(t: Time) -> Result<NaiveTime, ()> {
+            // 24:00:00 can't be represented
+            let secs = (t.0 / 1_000_000) as u32;
+            NaiveTime::from_num_seconds_from_midnight_opt(secs, (t.0 % 1_000_000) as u32 * 1000).ok_or(())
+        }
+    }
+
+    impl TryFrom<NaiveDateTime> for Timestamp {
+        type Error = ();
+        fn try_from(t: NaiveDateTime) -> Result<Timestamp, ()> {
+            Timestamp::from_unix_micros(t.and_utc().timestamp_micros()).ok_or(())
+        }
+    }
+    impl TryFrom<Timestamp> for NaiveDateTime {
+        type Error = ();
+        fn try_from(t: Timestamp) -> Result<NaiveDateTime, ()> {
+            DateTime::from_timestamp_micros(t.to_unix_micros().ok_or(())?).map(|t| t.naive_utc()).ok_or(())
+        }
+    }
+
+    impl TryFrom<DateTime<Utc>> for TimestampTz {
+        type Error = ();
+        fn try_from(t: DateTime<Utc>) -> Result<TimestampTz, ()> {
+            TimestampTz::from_unix_micros(t.timestamp_micros()).ok_or(())
+        }
+    }
+    impl TryFrom<TimestampTz> for DateTime<Utc> {
+        type Error = ();
+        fn try_from(t: TimestampTz) -> Result<DateTime<Utc>, ()> {
+            DateTime::from_timestamp_micros(t.to_unix_micros().ok_or(())?).ok_or(())
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+mod time_impls {
+    extern crate time;
+
+    use std::convert::TryFrom;
+    use self::time::{Date as TDate, Time as TTime, PrimitiveDateTime, OffsetDateTime};
+    use super::*;
+
+    impl TryFrom<TDate> for Date {
+        type Error = ();
+        fn try_from(d: TDate) -> Result<Date, ()> {
+            Date::from_unix_days((d - TDate::from_ordinal_date(1970, 1).unwrap()).whole_days()).ok_or(())
+        }
+    }
+    impl TryFrom<Date> for TDate {
+        type Error = ();
+        fn try_from(d: Date) -> Result<TDate, ()> {
+            let days = d.to_unix_days().ok_or(())?;
+            TDate::from_ordinal_date(1970, 1).unwrap().checked_add(time::Duration::days(days)).ok_or(())
+        }
+    }
+
+    impl From<TTime> for Time {
+        fn from(t: TTime) -> Time {
+            let (h, m, s, micro) = t.as_hms_micro();
+            Time((h as i64 * 3600 + m as i64 * 60 + s as i64) * 1_000_000 + micro as i64)
+        }
+    }
+    impl TryFrom<Time> for TTime {
+        type Error = ();
+        fn try_from(t: Time) -> Result<TTime, ()> {
+            let secs = t.0 / 1_000_000;
+            TTime::from_hms_micro((secs / 3600) as u8, (secs / 60 % 60) as u8, (secs % 60) as u8, (t.0 % 1_000_000) as u32).map_err(|_| ())
+        }
+    }
+
+    fn from_unix_micros(micros: i64) -> Result<OffsetDateTime, ()> {
+        OffsetDateTime::from_unix_timestamp_nanos(micros as i128 * 1000).map_err(|_| ())
+    }
+
+    impl TryFrom<PrimitiveDateTime> for Timestamp {
+        type Error = ();
+        fn try_from(t: PrimitiveDateTime) -> Result<Timestamp, ()> {
+            let micros = t.assume_utc().unix_timestamp_nanos() / 1000;
+            Timestamp::from_unix_micros(micros as i64).ok_or(())
+        }
+    }
+    impl TryFrom<Timestamp> for PrimitiveDateTime {
+        type Error = ();
+        fn try_from(t: Timestamp) -> Result<PrimitiveDateTime, ()> {
+            from_unix_micros(t.to_unix_micros().ok_or(())?).map(|t| PrimitiveDateTime::new(t.date(), t.time()))
+        }
+    }
+
+    impl TryFrom<OffsetDateTime> for TimestampTz {
+        type Error = ();
+        fn try_from(t: OffsetDateTime) -> Result<TimestampTz, ()> {
+            TimestampTz::from_unix_micros((t.unix_timestamp_nanos() / 1000) as i64).ok_or(())
+        }
+    }
+    impl TryFrom<TimestampTz> for OffsetDateTime {
+        type Error = ();
+        fn try_from(t: TimestampTz) -> Result<OffsetDateTime, ()> {
+            from_unix_micros(t.to_unix_micros().ok_or(())?)
+        }
+    }
+}
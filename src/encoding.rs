@@ -0,0 +1,77 @@
+use std::os::raw::{c_char, c_void};
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::{panic, str};
+
+use error::{self, SqlError};
+use sqlstate::SqlState;
+
+// PgEncoding is generated from enum pg_enc, compare mb/pg_wchar.h
+include!(concat!(env!("OUT_DIR"), "/encoding.rs"));
+
+extern "C" {
+    fn GetDatabaseEncoding() -> i32;
+    fn pg_do_encoding_conversion(src: *const u8, len: i32, src_encoding: i32, dest_encoding: i32) -> *const u8;
+    fn pg_any_to_server(s: *const c_char, len: i32, encoding: i32) -> *const c_char;
+    fn pfree(ptr: *mut c_void);
+}
+
+pub fn database() -> PgEncoding {
+    let id = unsafe { GetDatabaseEncoding() };
+    PgEncoding::from_id(id).unwrap_or_else(|| panic!("unknown database encoding {}", id))
+}
+
+// SQL_ASCII databases can contain anything, so there is nothing to convert from
+// (untranslatable characters in other encodings raise the usual postgres error)
+#[derive(Debug)]
+pub struct ConversionError;
+
+impl ConversionError {
+    pub fn raise(self) -> ! {
+        panic::panic_any(SqlError::new(SqlState::CharacterNotInRepertoire,
+            "invalid byte sequence for encoding \"UTF8\" in SQL_ASCII database"))
+    }
+}
+
+// converts a string in the database encoding (e.g. the payload of a text) to UTF-8
+// only allocates if the database encoding isn't UTF-8 (or SQL_ASCII)
+pub fn server_to_utf8(bytes: &[u8]) -> Result<Cow<str>, ConversionError> {
+    match database() {
+        // postgres has already validated this
+        PgEncoding::Utf8 => Ok(Cow::Borrowed(unsafe { str::from_utf8_unchecked(bytes) })),
+        PgEncoding::SqlAscii => str::from_utf8(bytes).map(Cow::Borrowed).map_err(|_| ConversionError),
+        encoding => unsafe {
+            let ptr = bytes.as_ptr();
+            // the value is valid in the database encoding, so this only fails for untranslatable characters
+            let converted = error::convert_postgres_error(|| {
+                pg_do_encoding_conversion(ptr, bytes.len() as i32, encoding as i32, PgEncoding::Utf8 as i32)
+            });
+
+            if converted == ptr {
+                // nothing had to be converted after all
+                Ok(Cow::Borrowed(str::from_utf8_unchecked(bytes)))
+            } else {
+                let ret = CStr::from_ptr(converted as *const c_char).to_str()
+                    .expect("encoding conversion produced invalid UTF-8").to_owned();
+                pfree(converted as *mut c_void);
+                Ok(Cow::Owned(ret))
+            }
+        }
+    }
+}
+
+// hands the value in the database encoding to f, which must copy it
+// raises the usual postgres errors for characters that can't be represented
+pub fn with_server_encoding<F: FnOnce(&[u8]) -> R, R>(value: &str, f: F) -> R {
+    unsafe {
+        let ptr = value.as_ptr() as *const c_char;
+        let converted = error::convert_postgres_error(|| pg_any_to_server(ptr, value.len() as i32, PgEncoding::Utf8 as i32));
+        if converted == ptr {
+            f(value.as_bytes())
+        } else {
+            let ret = f(CStr::from_ptr(converted).to_bytes());
+            pfree(converted as *mut c_void);
+            ret
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use Datum;
+use types::{FromDatum, StaticallyTyped, Oid};
+use sqlstate::SqlState;
+use catalog;
+use error;
+
+// compare CREATE TYPE ... AS ENUM: a value is the oid of its pg_enum row,
+// so the mapping to Rust goes through the label
+
+// usually implemented with PG_ENUM!
+pub trait PgEnum: Sized {
+    fn from_label(label: &str) -> Option<Self>;
+    fn label(&self) -> &'static str;
+}
+
+// a value of any enum type, the anyenum pseudo-type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnumValue(pub Oid);
+
+impl EnumValue {
+    // compare enum_in
+    pub fn from_label(enum_type: Oid, label: &str) -> EnumValue {
+        match catalog::enum_value(enum_type, label) {
+            Some(oid) => EnumValue(oid),
+            None => error::raise(SqlState::InvalidTextRepresentation,
+                                 format!("invalid input value for enum {}: \"{}\"",
+                                         catalog::get_type_name(enum_type).unwrap_or_default(), label)),
+        }
+    }
+
+    // e.g. EnumValue::new(Mood::Happy, ctx.fcinfo().return_type())
+    pub fn new<T: PgEnum>(value: T, enum_type: Oid) -> EnumValue {
+        EnumValue::from_label(enum_type, value.label())
+    }
+
+    // compare enum_out
+    pub fn label(&self) -> String {
+        catalog::enum_label(self.0).unwrap_or_else(||
+            error::raise(SqlState::InvalidBinaryRepresentation, format!("invalid internal value for enum: {}", (self.0).0)))
+    }
+
+    // None if the Rust enum has no variant for the label, e.g. after ALTER TYPE ... ADD VALUE
+    pub fn get<T: PgEnum>(&self) -> Option<T> {
+        T::from_label(&self.label())
+    }
+}
+
+unsafe impl StaticallyTyped for EnumValue { const OID: Oid = Oid(3500); }
+impl<'a> From<EnumValue> for Datum<'a> { fn from(e: EnumValue) -> Datum<'a> { e.0.into() } }
+impl<'a> FromDatum<'a> for EnumValue { unsafe fn from(d: Datum<'a>) -> EnumValue { EnumValue(FromDatum::from(d)) } }
+
+// declares a Rust enum whose variants map to the labels of a Postgres enum:
+// PG_ENUM! { pub enum Mood { Sad = "sad", Ok = "ok", Happy = "happy" } }
+// labels that are missing on either side only fail when a value is converted
+#[macro_export]
+macro_rules! PG_ENUM {
+    ( $(#[$attr:meta])* pub enum $name:ident { $( $variant:ident = $label:tt ),* $(,)* } ) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $( $variant ),*
+        }
+
+        impl $crate::enums::PgEnum for $name {
+            fn from_label(label: &str) -> Option<$name> {
+                match label {
+                    $( $label => Some($name::$variant), )*
+                    _ => None,
+                }
+            }
+
+            fn label(&self) -> &'static str {
+                match *self {
+                    $( $name::$variant => $label ),*
+                }
+            }
+        }
+    }
+}
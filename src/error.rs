@@ -2,35 +2,246 @@ pub extern crate unreachable;
 
 use std::os::raw::c_char;
 use std::{ptr, mem, thread};
-use std::ffi::CString;
-use std::panic::{self, UnwindSafe};
+use std::ffi::{CStr, CString};
+use std::panic::{self, UnwindSafe, PanicHookInfo};
 use std::error::Error;
 use std::fmt::{Debug, Display, Result as FmtResult, Formatter};
 use std::any::Any;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::ops::Deref;
+use std::backtrace::Backtrace;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicBool, AtomicU64, Ordering};
+use std::os::raw::c_void;
 
-use alloc::MemoryContext;
+use alloc::{self, MemoryContext};
+use guc::{self, GucContext};
+use stdio;
+use sqlstate::SqlState;
 
 extern "C" {
-    fn errstart(level: i32, filename: *const c_char, line: i32, funcname: *const c_char, domain: *const c_char) -> u32;
+    fn errcode(sqlerrcode: i32) -> i32;
     fn errmsg(fmt: *const c_char, ...);
+    fn errdetail_log(fmt: *const c_char, ...);
+
+    fn MemoryContextStrdup(context: *mut c_void, s: *const c_char) -> *const c_char;
+
+    fn RegisterXactCallback(callback: unsafe extern "C" fn(event: i32, arg: *mut c_void), arg: *mut c_void);
+}
+
+// the error location moved from errstart to errfinish in 13
+#[cfg(not(errfinish_location))]
+extern "C" {
+    fn errstart(level: i32, filename: *const c_char, line: i32, funcname: *const c_char, domain: *const c_char) -> u8;
     fn errfinish(dummy: i32, ...);
 }
 
+#[cfg(errfinish_location)]
+extern "C" {
+    #[link_name = "errstart"]
+    fn errstart_domain(level: i32, domain: *const c_char) -> u8;
+    #[link_name = "errfinish"]
+    fn errfinish_location(filename: *const c_char, line: i32, funcname: *const c_char);
+}
+
+#[cfg(not(errfinish_location))]
+unsafe fn start_report(level: i32, filename: *const c_char, line: i32, funcname: *const c_char) -> bool {
+    errstart(level, filename, line, funcname, ptr::null()) != 0
+}
+
+#[cfg(not(errfinish_location))]
+unsafe fn finish_report(_filename: *const c_char, _line: i32, _funcname: *const c_char) {
+    errfinish(0);
+}
+
+#[cfg(errfinish_location)]
+unsafe fn start_report(level: i32, _filename: *const c_char, _line: i32, _funcname: *const c_char) -> bool {
+    errstart_domain(level, ptr::null()) != 0
+}
+
+#[cfg(errfinish_location)]
+unsafe fn finish_report(filename: *const c_char, line: i32, funcname: *const c_char) {
+    errfinish_location(filename, line, funcname);
+}
+
+// compare elog.h, the values changed in 14 (WARNING_CLIENT_ONLY)
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Debug5 = ::ELEVEL_DEBUG5,
+    Debug4 = ::ELEVEL_DEBUG4,
+    Debug3 = ::ELEVEL_DEBUG3,
+    Debug2 = ::ELEVEL_DEBUG2,
+    Debug1 = ::ELEVEL_DEBUG1,
+    Log = ::ELEVEL_LOG,
+    LogServerOnly = ::ELEVEL_LOG_SERVER_ONLY,
+    Info = ::ELEVEL_INFO,
+    Notice = ::ELEVEL_NOTICE,
+    Warning = ::ELEVEL_WARNING,
+    Error = ::ELEVEL_ERROR,
+    Fatal = ::ELEVEL_FATAL,
+    Panic = ::ELEVEL_PANIC,
+}
+
+impl Level {
+    pub fn from_elevel(elevel: i32) -> Option<Level> {
+        Some(match elevel {
+            ::ELEVEL_DEBUG5 => Level::Debug5,
+            ::ELEVEL_DEBUG4 => Level::Debug4,
+            ::ELEVEL_DEBUG3 => Level::Debug3,
+            ::ELEVEL_DEBUG2 => Level::Debug2,
+            ::ELEVEL_DEBUG1 => Level::Debug1,
+            ::ELEVEL_LOG => Level::Log,
+            ::ELEVEL_LOG_SERVER_ONLY => Level::LogServerOnly,
+            ::ELEVEL_INFO => Level::Info,
+            ::ELEVEL_NOTICE => Level::Notice,
+            ::ELEVEL_WARNING => Level::Warning,
+            ::ELEVEL_ERROR => Level::Error,
+            ::ELEVEL_FATAL => Level::Fatal,
+            ::ELEVEL_PANIC => Level::Panic,
+            _ => return None,
+        })
+    }
+}
+
+// emit a message below error level (use panic!() for errors)
+pub fn report(level: Level, message: &str) {
+    let message = CString::new(message.replace('\0', "\\0")).unwrap();
+    convert_postgres_error(|| unsafe { report_raw(level, &message) })
+}
+
+// NB: this can longjmp, so only call it from within catch_postgres_error
+pub unsafe fn report_raw(level: Level, message: &CStr) {
+    assert!((level as i32) < Level::Error as i32, "use panic!() to raise errors");
+
+    if start_report(level as i32, ptr::null(), 0, ptr::null()) {
+        errmsg(b"%s\0" as *const _ as *const _, message.as_ptr());
+        finish_report(ptr::null(), 0, ptr::null());
+    }
+}
+
 static RUST_PANIC_FUNCNAME: [u8; 11] = *b"RUST PANIC\0";
 fn rust_panic_funcname_ptr() -> *const c_char { RUST_PANIC_FUNCNAME.as_ptr() as *const c_char }
 
+// where a panic happened, recorded by our panic hook
+struct PanicLocation {
+    file: &'static CStr,
+    line: u32,
+    column: u32,
+    backtrace: Option<Backtrace>,
+}
+
+// a panic payload on its way through postgres, together with its location
+struct InFlightPanic {
+    id: u64,
+    payload: Box<Any + Send>,
+    location: Option<Box<PanicLocation>>,
+}
+
+// panics currently travelling through postgres (innermost last).
+// nested conversions (rust -> pg -> rust -> pg) simply push more entries,
+// the ErrorData carries the id of its entry in detail_log (see panic_id)
+static IN_FLIGHT_PANICS: Mutex<Vec<InFlightPanic>> = Mutex::new(Vec::new());
+static NEXT_PANIC_ID: AtomicU64 = AtomicU64::new(1);
+
 // postgres is single-threaded software.
-static LAST_RUST_PANIC: AtomicPtr<Box<Any + Send>> = AtomicPtr::new(ptr::null_mut());
+static LAST_PANIC_LOCATION: AtomicPtr<PanicLocation> = AtomicPtr::new(ptr::null_mut());
+
+// GUC rust.panic_backtrace
+static PANIC_BACKTRACE: AtomicBool = AtomicBool::new(false);
+
+// postgres does not copy the filename we pass to errstart, so it has to outlive the error.
+// panic locations are a finite set so we can simply intern (and leak) them
+static PANIC_FILENAMES: Mutex<Vec<&'static CStr>> = Mutex::new(Vec::new());
+
+fn intern_filename(file: &str) -> &'static CStr {
+    let mut filenames = PANIC_FILENAMES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(&cs) = filenames.iter().find(|cs| cs.to_bytes() == file.as_bytes()) {
+        return cs;
+    }
+
+    let cs = CString::new(file).unwrap_or_else(|_| CString::new("<invalid filename>").unwrap());
+    let cs: &'static CStr = Box::leak(cs.into_boxed_c_str());
+    filenames.push(cs);
+    cs
+}
+
+fn replace_panic_location(new: *mut PanicLocation) {
+    let old = LAST_PANIC_LOCATION.swap(new, Ordering::Relaxed);
+    if !old.is_null() {
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+fn take_panic_location() -> Option<Box<PanicLocation>> {
+    let ptr = LAST_PANIC_LOCATION.swap(ptr::null_mut(), Ordering::Relaxed);
+    if ptr.is_null() {
+        None
+    } else {
+        Some(unsafe { Box::from_raw(ptr) })
+    }
+}
+
+fn in_flight_panics() -> ::std::sync::MutexGuard<'static, Vec<InFlightPanic>> {
+    IN_FLIGHT_PANICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+const PANIC_ID_PREFIX: &str = "Rust panic #";
+
+unsafe fn panic_id(e: &ErrorData) -> Option<u64> {
+    if e.funcname != rust_panic_funcname_ptr() || e.detail_log.is_null() {
+        return None;
+    }
+
+    let detail = CStr::from_ptr(e.detail_log).to_str().ok()?;
+    if !detail.starts_with(PANIC_ID_PREFIX) {
+        return None;
+    }
+    let digits = &detail[PANIC_ID_PREFIX.len()..];
+    let end = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+    digits[..end].parse().ok()
+}
+
+// errors that escape to the top level are never converted back, so drop their payloads eventually.
+// whatever still refers to them afterwards simply comes back as a plain PgError.
+unsafe extern "C" fn xact_callback(event: i32, _arg: *mut c_void) {
+    // XACT_EVENT_COMMIT, _PARALLEL_COMMIT, _ABORT, _PARALLEL_ABORT, _PREPARE
+    if event <= 4 {
+        in_flight_panics().clear();
+    }
+}
+
+fn panic_hook(info: &PanicHookInfo) {
+    // postgres errors travelling through rust are not interesting
+    if info.payload().is::<PgError>() {
+        return;
+    }
+
+    let location = info.location().map(|l| PanicLocation {
+        file: intern_filename(l.file()),
+        line: l.line(),
+        column: l.column(),
+        backtrace: if PANIC_BACKTRACE.load(Ordering::Relaxed) { Some(Backtrace::force_capture()) } else { None },
+    });
+    replace_panic_location(location.map(|l| Box::into_raw(Box::new(l))).unwrap_or(ptr::null_mut()));
+}
+
+// to be called from _PG_init
+// NB: this replaces the default panic hook - panics are reported through ereport anyways
+pub fn init() {
+    guc::define_bool(b"rust.panic_backtrace\0",
+                     b"Capture backtraces of Rust panics in the server log.\0",
+                     &PANIC_BACKTRACE,
+                     GucContext::Suset);
+    panic::set_hook(Box::new(panic_hook));
+    convert_postgres_error(|| unsafe { RegisterXactCallback(xact_callback, ptr::null_mut()) });
+}
 
 // this is a formality as it can't ever happen
 // in theory it would avoid a potential memory leak if you unload and re-load us a lot of times
 #[doc(hidden)]
 pub unsafe extern "C" fn _PG_fini() {
-    let ptr = LAST_RUST_PANIC.load(Ordering::Relaxed);
-    if !ptr.is_null() {
-        drop(Box::from_raw(ptr));
-    }
+    in_flight_panics().clear();
+    replace_panic_location(ptr::null_mut());
 }
 
 #[inline(always)]
@@ -46,24 +257,42 @@ fn convert_rust_panic_inner(e: Box<Any + Send>) -> ! {
             Err(e) => e,
         };
 
-        if errstart(20, ptr::null(), 0, rust_panic_funcname_ptr(), ptr::null()) != 0 {
-            // TODO: errcode or some shit
+        let location = take_panic_location();
+        let (filename, line) = location.as_ref().map(|l| (l.file.as_ptr(), l.line as i32)).unwrap_or((ptr::null(), 0));
+        if start_report(Level::Error as i32, filename, line, rust_panic_funcname_ptr()) {
+            if let Some(sql_error) = e.downcast_ref::<SqlError>() {
+                errcode(sql_error.code.sqlerrcode());
+            }
+
             {
-                let text = e.downcast_ref::<&str>().cloned().or_else(|| e.downcast_ref::<String>().map(|s| &s[..])).unwrap_or("<no text>");
+                let text = e.downcast_ref::<&str>().cloned()
+                    .or_else(|| e.downcast_ref::<String>().map(|s| &s[..]))
+                    .or_else(|| e.downcast_ref::<SqlError>().map(|e| &e.message[..]))
+                    .unwrap_or("<no text>");
                 match CString::new(text) {
                     Ok(text_cs) => errmsg(b"%s\0" as *const _ as *const _, text_cs.as_ptr()),
                     Err(_) => errmsg(b"<string conversion error>\0" as *const _ as *const _),
                 }
             }
 
-            // replace LAST_RUST_PANIC
-            let new_panic = Box::into_raw(Box::new(e));
-            let old_panic = LAST_RUST_PANIC.swap(new_panic, Ordering::Relaxed);
-            if !old_panic.is_null() {
-                drop(Box::from_raw(old_panic));
+            // the panic id, column and backtrace only go to the server log
+            let id = NEXT_PANIC_ID.fetch_add(1, Ordering::Relaxed);
+            {
+                let mut detail = format!("{}{}", PANIC_ID_PREFIX, id);
+                if let Some(ref l) = location {
+                    detail.push_str(&format!(" at {}:{}:{}", l.file.to_string_lossy(), l.line, l.column));
+                    if let Some(ref bt) = l.backtrace {
+                        detail.push_str(&format!("\n{}", bt));
+                    }
+                }
+                let detail_cs = CString::new(detail).unwrap_or_else(|_| CString::new(format!("{}{}", PANIC_ID_PREFIX, id)).unwrap());
+                errdetail_log(b"%s\0" as *const _ as *const _, detail_cs.as_ptr());
             }
 
-            errfinish(0);
+            // can't hold the lock across errfinish (it longjmps)
+            in_flight_panics().push(InFlightPanic { id, payload: e, location });
+
+            finish_report(filename, line, rust_panic_funcname_ptr());
         }
         self::unreachable::unreachable()
     }
@@ -80,11 +309,16 @@ extern "C" {
     // fn FreeErrorData(ed: *mut ErrorData);
     fn ReThrowError(ed: *mut ErrorData) -> !;
     fn pg_re_throw() -> !;
+
+    fn BeginInternalSubTransaction(name: *const c_char);
+    fn ReleaseCurrentSubTransaction();
+    fn RollbackAndReleaseCurrentSubTransaction();
+    static mut CurrentResourceOwner: *mut c_void;
 }
 
 #[repr(C)]
 #[derive(Debug)]
-struct ErrorData {
+pub struct ErrorData {
     elevel: i32,
     output_to_server: u8,
     output_to_client: u8,
@@ -102,6 +336,10 @@ struct ErrorData {
     detail_log: *const c_char,
     hint: *const c_char,
     context: *const c_char,
+    #[cfg(errordata_backtrace)]
+    backtrace: *const c_char,
+    #[cfg(not(postgres = "9.5"))]
+    message_id: *const c_char,
     schema_name: *const c_char,
     table_name: *const c_char,
     column_name: *const c_char,
@@ -115,11 +353,89 @@ struct ErrorData {
     assoc_context: MemoryContext<'static>, // imperfect approximation
 }
 
+unsafe fn opt_cstr<'a>(ptr: *const c_char) -> Option<&'a CStr> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr))
+    }
+}
+
+// NB: all strings are in the server encoding
+impl ErrorData {
+    pub fn elevel(&self) -> i32 { self.elevel }
+    pub fn level(&self) -> Option<Level> { Level::from_elevel(self.elevel) }
+    pub fn output_to_server(&self) -> bool { self.output_to_server != 0 }
+    pub fn output_to_client(&self) -> bool { self.output_to_client != 0 }
+    pub fn filename(&self) -> Option<&CStr> { unsafe { opt_cstr(self.filename) } }
+    pub fn lineno(&self) -> i32 { self.lineno }
+    pub fn funcname(&self) -> Option<&CStr> { unsafe { opt_cstr(self.funcname) } }
+    pub fn sqlerrcode(&self) -> i32 { self.sqlerrcode }
+    pub fn message(&self) -> Option<&CStr> { unsafe { opt_cstr(self.message) } }
+    pub fn detail(&self) -> Option<&CStr> { unsafe { opt_cstr(self.detail) } }
+    pub fn detail_log(&self) -> Option<&CStr> { unsafe { opt_cstr(self.detail_log) } }
+    pub fn hint(&self) -> Option<&CStr> { unsafe { opt_cstr(self.hint) } }
+    pub fn context(&self) -> Option<&CStr> { unsafe { opt_cstr(self.context) } }
+    pub fn schema_name(&self) -> Option<&CStr> { unsafe { opt_cstr(self.schema_name) } }
+    pub fn table_name(&self) -> Option<&CStr> { unsafe { opt_cstr(self.table_name) } }
+    pub fn column_name(&self) -> Option<&CStr> { unsafe { opt_cstr(self.column_name) } }
+    pub fn datatype_name(&self) -> Option<&CStr> { unsafe { opt_cstr(self.datatype_name) } }
+    pub fn constraint_name(&self) -> Option<&CStr> { unsafe { opt_cstr(self.constraint_name) } }
+    pub fn cursorpos(&self) -> i32 { self.cursorpos }
+    pub fn internalpos(&self) -> i32 { self.internalpos }
+    pub fn internalquery(&self) -> Option<&CStr> { unsafe { opt_cstr(self.internalquery) } }
+
+    // None for codes that are not in errcodes.txt (e.g. custom codes from other extensions)
+    pub fn sqlstate(&self) -> Option<SqlState> {
+        SqlState::from_sqlerrcode(self.sqlerrcode)
+    }
+
+    // the setters are only useful from an emit_log_hook (see log_hook)
+    // NB: new strings are allocated in the error's own memory context
+    pub fn set_output_to_server(&mut self, value: bool) { self.output_to_server = value as u8; }
+    pub fn set_output_to_client(&mut self, value: bool) { self.output_to_client = value as u8; }
+    pub fn set_message(&mut self, message: &str) { self.message = self.strdup(message); }
+    pub fn set_detail(&mut self, detail: Option<&str>) { self.detail = detail.map(|s| self.strdup(s)).unwrap_or(ptr::null()); }
+    pub fn set_hint(&mut self, hint: Option<&str>) { self.hint = hint.map(|s| self.strdup(s)).unwrap_or(ptr::null()); }
+
+    // no catch_postgres_error here: this runs while the error is being reported,
+    // and catching would flush the very error stack that is being emitted
+    fn strdup(&self, s: &str) -> *const c_char {
+        let cs = CString::new(s.replace('\0', "\\0")).unwrap();
+        unsafe { MemoryContextStrdup(self.assoc_context.as_ptr(), cs.as_ptr()) }
+    }
+}
+
 // TODO: error builder api so I can construct one myself and pass it to panic!()
 // this is mostly relevant so I can report my own sql-compatible errors
 
+// panic!() with this to raise an error with a proper SQLSTATE
+// (plain string panics are reported as internal_error)
+#[derive(Debug, Clone)]
+pub struct SqlError {
+    pub code: SqlState,
+    pub message: String,
+}
+
+impl SqlError {
+    pub fn new<S: Into<String>>(code: SqlState, message: S) -> SqlError {
+        SqlError { code, message: message.into() }
+    }
+}
+
+pub fn raise<S: Into<String>>(code: SqlState, message: S) -> ! {
+    panic::panic_any(SqlError::new(code, message))
+}
+
 pub struct PgError(*mut ErrorData);
 unsafe impl Send for PgError {}
+impl Deref for PgError {
+    type Target = ErrorData;
+
+    fn deref(&self) -> &ErrorData {
+        unsafe { &*self.0 }
+    }
+}
 impl PgError {
     pub unsafe fn rethrow(self) -> ! {
         // bending postgres memory allocation to rust is hard
@@ -152,7 +468,10 @@ impl Debug for PgError {
 }
 impl Display for PgError {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        write!(fmt, "lul")
+        match self.message() {
+            Some(message) => write!(fmt, "{}", message.to_string_lossy()),
+            None => write!(fmt, "<no message>"),
+        }
     }
 }
 impl Error for PgError {
@@ -178,28 +497,71 @@ pub fn convert_postgres_error_dtor<F: FnOnce() + UnwindSafe>(f: F) {
 #[inline(never)]
 fn convert_postgres_error_inner(e: PgError) -> ! {
     unsafe {
-        // check ptr equality for our magic funcname
-        if (*e.0).funcname == rust_panic_funcname_ptr() {
-            let ptr = LAST_RUST_PANIC.swap(ptr::null_mut(), Ordering::Relaxed);
-            assert!(!ptr.is_null());
-            let payload = Box::from_raw(ptr);
-
-            panic::resume_unwind(*payload)
-        } else {
+        let in_flight = panic_id(&*e.0).and_then(|id| {
+            let mut panics = in_flight_panics();
+            let pos = panics.iter().rposition(|p| p.id == id)?;
+            Some(panics.remove(pos))
+        });
+
+        match in_flight {
+            Some(InFlightPanic { payload, location, .. }) => {
+                // resume_unwind bypasses the panic hook, so restore the location for whoever converts this next
+                replace_panic_location(location.map(Box::into_raw).unwrap_or(ptr::null_mut()));
+                panic::resume_unwind(payload)
+            }
             // just throw as PgError
-            panic!(e)
+            None => panic!(e),
         }
     }
 }
 
+// NB: this does not clean up after the error (and leaves CurrentMemoryContext pointing elsewhere),
+// so the error must be rethrown. use catch_postgres_error_subxact to actually handle it
 #[inline]
 pub fn catch_postgres_error<F: FnOnce() -> R + UnwindSafe, R>(f: F) -> Result<R, PgError> {
+    unsafe { catch_with(f, || record_pg_error()) }
+}
+
+// runs f in a subtransaction that is rolled back if it fails, compare exception blocks in pl_exec.c
+// the error data is copied into the caller's memory context
+pub fn catch_postgres_error_subxact<F: FnOnce() -> R + UnwindSafe, R>(f: F) -> Result<R, PgError> {
     unsafe {
+        let ctx = alloc::get_current_ctx();
+        let owner = CurrentResourceOwner;
+        convert_postgres_error(|| BeginInternalSubTransaction(ptr::null()));
+        ctx.set_current();
+
+        let ret = catch_with(f, || {
+            ctx.set_current();
+            let err = PgError(CopyErrorData());
+            FlushErrorState();
+            err
+        });
+
+        if ret.is_ok() {
+            convert_postgres_error(|| ReleaseCurrentSubTransaction());
+        } else {
+            convert_postgres_error(|| RollbackAndReleaseCurrentSubTransaction());
+        }
+        ctx.set_current();
+        CurrentResourceOwner = owner;
+        ret
+    }
+}
+
+#[inline]
+unsafe fn catch_with<F: FnOnce() -> R + UnwindSafe, R, E: FnOnce() -> PgError>(f: F, record: E) -> Result<R, PgError> {
+    {
         let restore = RestorePgExceptionStack::capture();
+        // dropped on every way out, including the longjmp below
+        let stdio = stdio::suspend();
         let mut jmpbuf: [u8; ::LEN_SIGJMPBUF] = mem::uninitialized();
 
         if sigsetjmp(jmpbuf.as_mut_ptr(), 0) == 0 {
             PG_exception_stack = jmpbuf.as_mut_ptr();
+            if let Some(ref stdio) = stdio {
+                stdio.forward();
+            }
 
             let ret = f();
             drop(restore);
@@ -207,7 +569,7 @@ pub fn catch_postgres_error<F: FnOnce() -> R + UnwindSafe, R>(f: F) -> Result<R,
         } else {
             drop(restore);
 
-            Err(record_pg_error())
+            Err(record())
         }
     }
 }
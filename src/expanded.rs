@@ -0,0 +1,197 @@
+use std::os::raw::c_void;
+use std::any::TypeId;
+use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
+use std::{mem, ptr, slice};
+
+use Datum;
+use types::FromDatum;
+use varlena::{self, Varlena, Toasted, Storage};
+use alloc::MemoryContext;
+use error;
+
+// compare utils/expandeddatum.h
+//
+// an expanded object lives in its own memory context and is referenced through special toast pointers.
+// postgres keeps passing those around instead of flattening the value on every call,
+// e.g. across iterations of a plpgsql loop or the transition calls of an aggregate.
+
+// what a Rust type needs to be kept in expanded form
+pub trait ExpandedObject: Sized + 'static {
+    // the SQL type this flattens to, e.g. bytea
+    type Flat: Varlena + ?Sized;
+
+    // size of the flat representation, excluding the varlena header
+    fn flat_size(&self) -> usize;
+
+    // payload is exactly flat_size() bytes, the header is filled in for you
+    fn flatten_into(&self, payload: &mut [u8]);
+}
+
+// VARHDRSZ_EXTERNAL + sizeof(varatt_expanded)
+const EXPANDED_POINTER_SIZE: usize = 2 + mem::size_of::<usize>();
+
+#[repr(C)]
+struct ExpandedObjectHeader {
+    vl_len_: i32, // EOH_HEADER_MAGIC
+    eoh_methods: *const ExpandedObjectMethods,
+    eoh_context: *mut c_void,
+    eoh_rw_ptr: [u8; EXPANDED_POINTER_SIZE],
+    eoh_ro_ptr: [u8; EXPANDED_POINTER_SIZE],
+}
+
+#[repr(C)]
+struct ExpandedObjectMethods {
+    get_flat_size: unsafe extern "C" fn(eohptr: *mut ExpandedObjectHeader) -> usize,
+    flatten_into: unsafe extern "C" fn(eohptr: *mut ExpandedObjectHeader, result: *mut u8, allocated_size: usize),
+}
+
+#[repr(C)]
+struct MemoryContextCallback {
+    func: unsafe extern "C" fn(arg: *mut c_void),
+    arg: *mut c_void,
+    next: *mut MemoryContextCallback,
+}
+
+extern "C" {
+    fn EOH_init_header(eohptr: *mut ExpandedObjectHeader, methods: *const ExpandedObjectMethods, obj_context: *mut c_void);
+    fn DatumGetEOHP(d: Datum) -> *mut ExpandedObjectHeader;
+    fn MemoryContextRegisterResetCallback(context: *mut c_void, cb: *mut MemoryContextCallback);
+}
+
+// everything up to the value has the same layout for all T
+// the methods live right in the object (there are no generic statics), which is also how we recognize our own objects
+#[repr(C)]
+struct BoxHead {
+    header: ExpandedObjectHeader,
+    methods: ExpandedObjectMethods,
+    type_id: TypeId,
+    callback: MemoryContextCallback,
+}
+
+#[repr(C)]
+struct ExpandedBox<T> {
+    head: BoxHead,
+    value: T,
+}
+
+unsafe extern "C" fn get_flat_size<T: ExpandedObject>(eohptr: *mut ExpandedObjectHeader) -> usize {
+    let value = AssertUnwindSafe(&(*(eohptr as *const ExpandedBox<T>)).value);
+    error::convert_rust_panic(|| 4 + value.flat_size())
+}
+
+unsafe extern "C" fn flatten_into<T: ExpandedObject>(eohptr: *mut ExpandedObjectHeader, result: *mut u8, allocated_size: usize) {
+    let value = AssertUnwindSafe(&(*(eohptr as *const ExpandedBox<T>)).value);
+    error::convert_rust_panic(|| {
+        let buf = slice::from_raw_parts_mut(result, allocated_size);
+        value.flatten_into(&mut buf[4..]);
+        varlena::set_varsize(result, allocated_size);
+    })
+}
+
+// runs when the object's memory context goes away
+unsafe extern "C" fn drop_value<T: ExpandedObject>(arg: *mut c_void) {
+    let value = AssertUnwindSafe(&mut (*(arg as *mut ExpandedBox<T>)).value as *mut T);
+    error::convert_rust_panic(|| ptr::drop_in_place(*value))
+}
+
+unsafe fn is_ours(eohptr: *mut ExpandedObjectHeader) -> bool {
+    let head = eohptr as *const BoxHead;
+    (*eohptr).eoh_methods == &(*head).methods as *const _
+}
+
+// a handle to an expanded object, either read/write (we may modify it in place) or read-only
+pub struct Expanded<'a, T: ExpandedObject> {
+    obj: *mut ExpandedBox<T>,
+    read_write: bool,
+    marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: ExpandedObject> Expanded<'a, T> {
+    // creates the object in a new memory context below parent
+    // an aggregate only keeps a transition state in expanded form if parent is FunctionCallContext::aggregate_allocator,
+    // anything else is flattened and copied after each call
+    pub fn new<'c>(value: T, parent: &'a MemoryContext<'c>) -> Expanded<'a, T> {
+        assert!(mem::align_of::<ExpandedBox<T>>() <= ::MAXIMUM_ALIGNOF, "expanded object is overaligned");
+
+        // postgres owns the context from now on, it gets deleted together with the object
+        let context = MemoryContext::create_allocset(Some(parent), 0, 8 * 1024, 8 * 1024 * 1024);
+        let context_ptr = context.as_ptr();
+        let obj = context.alloc_undef(mem::size_of::<ExpandedBox<T>>()) as *mut ExpandedBox<T>;
+        mem::forget(context);
+
+        unsafe {
+            ptr::write(&mut (*obj).value, value);
+            ptr::write(&mut (*obj).head.methods, ExpandedObjectMethods {
+                get_flat_size: get_flat_size::<T>,
+                flatten_into: flatten_into::<T>,
+            });
+            ptr::write(&mut (*obj).head.type_id, TypeId::of::<T>());
+            ptr::write(&mut (*obj).head.callback, MemoryContextCallback {
+                func: drop_value::<T>,
+                arg: obj as *mut c_void,
+                next: ptr::null_mut(),
+            });
+
+            let (header, methods, callback) = (&mut (*obj).head.header as *mut _, &(*obj).head.methods as *const _, &mut (*obj).head.callback as *mut _);
+            error::convert_postgres_error(|| {
+                EOH_init_header(header, methods, context_ptr);
+                MemoryContextRegisterResetCallback(context_ptr, callback);
+            });
+
+            Expanded { obj, read_write: true, marker: PhantomData }
+        }
+    }
+
+    pub fn get(&self) -> &T {
+        unsafe { &(*self.obj).value }
+    }
+
+    // None for read-only pointers, those belong to someone else
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.read_write {
+            unsafe { Some(&mut (*self.obj).value) }
+        } else {
+            None
+        }
+    }
+
+    pub fn is_read_write(&self) -> bool {
+        self.read_write
+    }
+
+    // hand out a read-only pointer, e.g. to store the value somewhere without giving up ownership
+    pub fn read_only(&self) -> Toasted<'a, T::Flat> {
+        unsafe { FromDatum::from(Datum::create((*self.obj).head.header.eoh_ro_ptr.as_ptr() as usize)) }
+    }
+}
+
+// returning a read/write pointer passes ownership of the object on to the caller
+impl<'a, T: ExpandedObject> From<Expanded<'a, T>> for Toasted<'a, T::Flat> {
+    fn from(e: Expanded<'a, T>) -> Toasted<'a, T::Flat> {
+        unsafe {
+            let header = &(*e.obj).head.header;
+            let ptr = if e.read_write { header.eoh_rw_ptr.as_ptr() } else { header.eoh_ro_ptr.as_ptr() };
+            FromDatum::from(Datum::create(ptr as usize))
+        }
+    }
+}
+
+impl<'a, F: Varlena + ?Sized> Toasted<'a, F> {
+    // only for objects created through Expanded::new with the same T
+    // anything else (including postgres' own expanded arrays and records) gives None
+    pub fn expanded<T: ExpandedObject<Flat = F>>(&self) -> Option<Expanded<'a, T>> {
+        let read_write = match self.storage() {
+            Storage::Expanded { read_write } => read_write,
+            _ => return None,
+        };
+        unsafe {
+            let ptr = self.as_ptr();
+            let eohptr = error::convert_postgres_error(|| DatumGetEOHP(Datum::create(ptr as usize)));
+            if !is_ours(eohptr) || (*(eohptr as *const BoxHead)).type_id != TypeId::of::<T>() {
+                return None;
+            }
+            Some(Expanded { obj: eohptr as *mut ExpandedBox<T>, read_write, marker: PhantomData })
+        }
+    }
+}
@@ -1,16 +1,70 @@
 use std::os::raw::c_void;
-use std::mem::{self, ManuallyDrop};
+use std::mem::ManuallyDrop;
+use std::ptr;
 
 use Datum;
 use catalog;
+use error;
 use alloc::{self, MemoryContext};
-use types::{StaticallyTyped, FromDatum, Oid, bytea};
-use varlena::Toasted;
+use types::{self, StaticallyTyped, FromDatum, Oid, bytea, text, name};
+use varlena::{self, Varlena};
+use array::{ArrayElement, ArrayBuilder};
 use spi::SpiContext;
+use stringinfo::TextWriter;
 
 extern "C" {
     pub fn get_fn_expr_argtype(flinfo: *mut FmgrInfo, argnum: i32) -> Oid;
     pub fn get_fn_expr_rettype(flinfo: *mut FmgrInfo) -> Oid;
+    fn exprTypmod(expr: *const c_void) -> i32;
+    fn AggCheckCallContext(fcinfo: *mut c_void, aggcontext: *mut *mut c_void) -> i32;
+    fn domain_check(value: Datum, isnull: u8, domain_type: Oid, extra: *mut *mut c_void, mcxt: *mut c_void);
+}
+
+// domains pass as their base type and any enum passes as anyenum
+fn type_matches(actual: Oid, expected: Oid) -> bool {
+    if actual == expected {
+        return true;
+    }
+    // the type couldn't be determined, getBaseType would raise for it
+    if actual == Oid(0) {
+        return false;
+    }
+    let base = catalog::base_type(actual);
+    base == expected || (expected == <types::anyenum as StaticallyTyped>::OID && catalog::is_enum(base))
+}
+
+// compare list_nth in nodes/pg_list.h
+unsafe fn list_nth(list: *const u8, n: usize) -> *const c_void {
+    // length directly follows the NodeTag in every version
+    if list.is_null() || n >= *(list.offset(4) as *const i32) as usize {
+        return ptr::null();
+    }
+
+    let cells = *(list.offset(::LIST_ELEMENTS_OFFSET as isize) as *const *const *const c_void);
+    if ::PG_VERSION >= 1300 {
+        // an array of pointer-sized cells
+        *cells.offset(n as isize)
+    } else {
+        // a linked list, the next pointer follows the pointer-sized data
+        let mut cell = cells;
+        for _ in 0..n {
+            cell = *(cell.offset(1) as *const *const *const c_void);
+        }
+        *cell
+    }
+}
+
+// compare get_call_expr_argtype, but we only care about plain calls
+unsafe fn call_expr_arg(expr: *const c_void, argnum: usize) -> *const c_void {
+    if expr.is_null() {
+        return ptr::null();
+    }
+    let args_offset = match *(expr as *const i32) {
+        ::T_FUNCEXPR => ::FUNCEXPR_ARGS_OFFSET,
+        ::T_OPEXPR => ::OPEXPR_ARGS_OFFSET,
+        _ => return ptr::null(),
+    };
+    list_nth(*((expr as *const u8).offset(args_offset as isize) as *const *const u8), argnum)
 }
 
 #[repr(C)]
@@ -64,6 +118,22 @@ impl<'a> FunctionCallInfo<'a> {
         }
     }
 
+    // -1 if unknown, which is always the case for function results unless it's a length coercion
+    pub fn return_typmod(&self) -> i32 {
+        unsafe {
+            let expr = (*(*self.0).flinfo).fn_expr;
+            if expr.is_null() { -1 } else { exprTypmod(expr) }
+        }
+    }
+
+    // e.g. the declared length + 4 for a varchar(n) column, -1 if unknown
+    pub fn arg_typmod(&self, n: usize) -> i32 {
+        unsafe {
+            let arg = call_expr_arg((*(*self.0).flinfo).fn_expr, n);
+            if arg.is_null() { -1 } else { exprTypmod(arg) }
+        }
+    }
+
     pub fn arg_types<'b>(&'b self) -> ArgTypesIter<'a, 'b> {
         ArgTypesIter {
             fcinfo: self,
@@ -83,7 +153,7 @@ impl<'a> FunctionCallInfo<'a> {
     // (instead of panicking)
     pub fn arg<T: StaticallyTyped + FromDatum<'a>>(&self, n: usize) -> Option<T> {
         let typ = self.arg_types().nth(n).expect("missing parameter");
-        if T::OID != typ {
+        if !type_matches(typ, T::OID) {
             panic!("Argument #{} has type {} but should be {}", n,
                    catalog::get_type_name(typ).unwrap(),
                    catalog::get_type_name(T::OID).unwrap());
@@ -113,12 +183,33 @@ impl<'a> FunctionCallInfo<'a> {
         }
     }
 
+    // the constraints of a domain return type are not checked by the executor, see check_return_domain
+    pub fn returns_domain(&self) -> bool {
+        let ret_type = self.return_type();
+        catalog::base_type(ret_type) != ret_type
+    }
+
+    // compare domain_check, raises if the value violates a constraint
+    pub fn check_return_domain(&self, value: Option<Datum<'a>>) {
+        let ret_type = self.return_type();
+        let (value, isnull) = match value {
+            Some(d) => (d, 0),
+            None => (Datum::create(0), 1),
+        };
+        unsafe {
+            // domain_check caches its state in fn_extra
+            let flinfo = (*self.0).flinfo;
+            let (extra, mcxt) = (&mut (*flinfo).fn_extra as *mut _, (*flinfo).fn_mcxt);
+            error::convert_postgres_error(|| domain_check(value, isnull, ret_type, extra, mcxt));
+        }
+    }
+
     #[inline(never)]
     pub fn typecheck(&self, ret_type: Oid, expected_types: &'static [Oid]) {
         // TODO: also print function name in error messages
 
         // 1. check return type
-        if self.return_type() != ret_type {
+        if !type_matches(self.return_type(), ret_type) {
             panic!("Return type is {} but should be {}",
                    catalog::get_type_name(self.return_type()).unwrap(),
                    catalog::get_type_name(ret_type).unwrap());
@@ -129,7 +220,7 @@ impl<'a> FunctionCallInfo<'a> {
         for (i, &expected) in expected_types.iter().enumerate() {
             let actual = arg_types.next().unwrap_or_else(||
                     panic!("Argument #{} is missing", i));
-            if actual != expected {
+            if !type_matches(actual, expected) {
                 panic!("Argument {} has type {} but should be {}", i,
                        catalog::get_type_name(actual).unwrap(),
                        catalog::get_type_name(expected).unwrap());
@@ -142,9 +233,16 @@ impl<'a> FunctionCallInfo<'a> {
 
 
     pub unsafe fn bootstrap(self) -> FunctionCallContext<'a> {
+        let mut aggcontext = ptr::null_mut();
+        let aggregate_allocator = if AggCheckCallContext(self.0 as *mut c_void, &mut aggcontext) != 0 {
+            Some(alloc::borrow_ctx(aggcontext))
+        } else {
+            None
+        };
         FunctionCallContext {
             fcinfo: self,
             allocator: alloc::get_current_ctx(),
+            aggregate_allocator,
             //spi: None,
             has_spi: AssertUnwindSafe(Cell::new(false)),
         }
@@ -215,6 +313,35 @@ impl<'a: 'b, 'b> ExactSizeIterator for ArgsIter<'a, 'b> {
 macro_rules! lifetimeize {
     (bytea) => ( $crate::varlena::Toasted<'a, $crate::types::bytea> );
     (text) => ( $crate::varlena::Toasted<'a, $crate::types::text> );
+    (name) => ( &'a $crate::types::name );
+    (numeric) => ( $crate::varlena::Toasted<'a, $crate::types::numeric> );
+    (jsonb) => ( $crate::varlena::Toasted<'a, $crate::types::jsonb> );
+    (inet) => ( $crate::varlena::Toasted<'a, $crate::types::inet> );
+    (cidr) => ( $crate::varlena::Toasted<'a, $crate::types::cidr> );
+    (varchar) => ( $crate::varlena::Toasted<'a, $crate::types::varchar> );
+    (bpchar) => ( $crate::varlena::Toasted<'a, $crate::types::bpchar> );
+    (boolarray) => ( $crate::varlena::Toasted<'a, $crate::types::boolarray> );
+    (byteaarray) => ( $crate::varlena::Toasted<'a, $crate::types::byteaarray<'a>> );
+    (chararray) => ( $crate::varlena::Toasted<'a, $crate::types::chararray> );
+    (int2array) => ( $crate::varlena::Toasted<'a, $crate::types::int2array> );
+    (int4array) => ( $crate::varlena::Toasted<'a, $crate::types::int4array> );
+    (textarray) => ( $crate::varlena::Toasted<'a, $crate::types::textarray<'a>> );
+    (int8array) => ( $crate::varlena::Toasted<'a, $crate::types::int8array> );
+    (float4array) => ( $crate::varlena::Toasted<'a, $crate::types::float4array> );
+    (float8array) => ( $crate::varlena::Toasted<'a, $crate::types::float8array> );
+    (oidarray) => ( $crate::varlena::Toasted<'a, $crate::types::oidarray> );
+    (int4range) => ( $crate::varlena::Toasted<'a, $crate::types::int4range> );
+    (numrange) => ( $crate::varlena::Toasted<'a, $crate::types::numrange<'a>> );
+    (tsrange) => ( $crate::varlena::Toasted<'a, $crate::types::tsrange> );
+    (tstzrange) => ( $crate::varlena::Toasted<'a, $crate::types::tstzrange> );
+    (daterange) => ( $crate::varlena::Toasted<'a, $crate::types::daterange> );
+    (int8range) => ( $crate::varlena::Toasted<'a, $crate::types::int8range> );
+    (int4multirange) => ( $crate::varlena::Toasted<'a, $crate::types::int4multirange> );
+    (nummultirange) => ( $crate::varlena::Toasted<'a, $crate::types::nummultirange<'a>> );
+    (tsmultirange) => ( $crate::varlena::Toasted<'a, $crate::types::tsmultirange> );
+    (tstzmultirange) => ( $crate::varlena::Toasted<'a, $crate::types::tstzmultirange> );
+    (datemultirange) => ( $crate::varlena::Toasted<'a, $crate::types::datemultirange> );
+    (int8multirange) => ( $crate::varlena::Toasted<'a, $crate::types::int8multirange> );
     ($other:ident) => ( $crate::types::$other );
 }
 
@@ -224,6 +351,7 @@ use std::panic::AssertUnwindSafe;
 pub struct FunctionCallContext<'a> {
     fcinfo: FunctionCallInfo<'a>,
     allocator: ManuallyDrop<MemoryContext<'static>>,
+    aggregate_allocator: Option<ManuallyDrop<MemoryContext<'static>>>,
     //spi: Option<SpiContext>;
     has_spi: AssertUnwindSafe<Cell<bool>>,
 }
@@ -239,16 +367,48 @@ impl<'a> FunctionCallContext<'a> {
     }
 
     pub fn alloc_bytea(&self, len: usize) -> &'a mut bytea {
-        #[allow(mutable_transmutes)]
         unsafe {
             let size = len + 4;
-            let ptr = self.allocator.alloc(size).as_mut_ptr() as *mut u32;
-            *ptr = (size as u32) << 2;
-            let shared: &'a bytea = <Toasted<bytea> as FromDatum>::from(Datum::create(ptr as usize)).to_varlena().unwrap();
-            mem::transmute(shared)
+            let ptr = self.allocator.alloc(size).as_mut_ptr();
+            varlena::set_varsize(ptr, size);
+            bytea::dst_ptrcast(ptr)
         }
     }
 
+    // converts to the database encoding
+    pub fn alloc_text(&self, value: &str) -> &'a text {
+        types::text_from_str(value, self.result_allocator())
+    }
+
+    pub fn text_writer(&self) -> TextWriter<'a> {
+        TextWriter::new(self.result_allocator())
+    }
+
+    pub fn alloc_name(&self, value: &str) -> &'a mut name {
+        unsafe {
+            let ptr = self.allocator.alloc(::NAMEDATALEN).as_mut_ptr() as *mut name;
+            let name: &'a mut name = &mut *ptr;
+            name.set(value);
+            name
+        }
+    }
+
+    // same context as allocator(), but borrowed for as long as the call lasts
+    // so that anything allocated in it can be returned
+    pub fn result_allocator(&self) -> &'a MemoryContext<'static> {
+        unsafe { &*(&*self.allocator as *const _) }
+    }
+
+    // when called as an aggregate or window transition function: the context that lives as long as the
+    // aggregate state, compare AggCheckCallContext
+    pub fn aggregate_allocator(&self) -> Option<&'a MemoryContext<'static>> {
+        self.aggregate_allocator.as_ref().map(|ctx| unsafe { &*(&**ctx as *const _) })
+    }
+
+    pub fn array_builder<T: ArrayElement + Into<Datum<'a>>>(&self) -> ArrayBuilder<'a, T> {
+        ArrayBuilder::new(self.result_allocator())
+    }
+
     pub fn connect_spi(&self) -> SpiContext {
         assert!(!self.has_spi.get(), "Multiple SPI connections are illegal!");
 
@@ -318,6 +478,7 @@ macro_rules! CREATE_FUNCTION {
             }
 
             static KNOWN_GOOD_OID: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::ATOMIC_USIZE_INIT;
+            static RETURNS_DOMAIN: ::std::sync::atomic::AtomicBool = ::std::sync::atomic::ATOMIC_BOOL_INIT;
             static EXPECTED_ARG_TYPES: &'static [$crate::types::Oid] = &[
                 $( <$crate::types::$argty as $crate::types::StaticallyTyped>::OID ),*
             ];
@@ -325,6 +486,7 @@ macro_rules! CREATE_FUNCTION {
             let ctx = fcinfo.bootstrap();
             let fcinfo = ctx.fcinfo();
             let ret = $crate::error::convert_rust_panic(|| {
+                let _stdio = $crate::stdio::capture();
                 let mut args = fcinfo.args();
                 let mut arg_types = fcinfo.arg_types();
 
@@ -337,6 +499,7 @@ macro_rules! CREATE_FUNCTION {
                 if my_oid_usz != KNOWN_GOOD_OID.load(::std::sync::atomic::Ordering::Relaxed) {
                     // first call, have to typecheck
                     fcinfo.typecheck($crate::types::$retty::OID, EXPECTED_ARG_TYPES);
+                    RETURNS_DOMAIN.store(fcinfo.returns_domain(), ::std::sync::atomic::Ordering::Relaxed);
                     // remember that this is done
                     KNOWN_GOOD_OID.store(my_oid_usz, ::std::sync::atomic::Ordering::Relaxed);
                 }
@@ -346,14 +509,19 @@ macro_rules! CREATE_FUNCTION {
                     let $argname = args.next().unwrap().map(|d| $crate::types::FromDatum::from(d)); // unwrap can't trigger, length is already checked
                 )*;
 
-                user_impl(&ctx,
+                let ret = user_impl(&ctx,
                     $(
                         $argname
                     ),*
-                )
+                ).map(|x| Datum::from(x));
+
+                if RETURNS_DOMAIN.load(::std::sync::atomic::Ordering::Relaxed) {
+                    fcinfo.check_return_domain(ret);
+                }
+                ret
             });
             match ret {
-                Some(x) => Datum::from(x),
+                Some(x) => x,
                 None => fcinfo.return_null(),
             }
         }
@@ -0,0 +1,58 @@
+use std::os::raw::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::ptr;
+
+use error;
+
+// compare utils/guc.h
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GucContext {
+    Internal = 0,
+    Postmaster = 1,
+    Sighup = 2,
+    SuBackend = 3,
+    Backend = 4,
+    Suset = 5,
+    Userset = 6,
+}
+
+extern "C" {
+    fn DefineCustomBoolVariable(name: *const c_char,
+                                short_desc: *const c_char,
+                                long_desc: *const c_char,
+                                value_addr: *mut bool,
+                                boot_value: bool,
+                                context: GucContext,
+                                flags: i32,
+                                check_hook: *const c_void,
+                                assign_hook: *const c_void,
+                                show_hook: *const c_void);
+}
+
+fn cstr_ptr(s: &'static [u8]) -> *const c_char {
+    assert_eq!(s.last(), Some(&0), "GUC strings must be nul-terminated");
+    s.as_ptr() as *const c_char
+}
+
+// postgres keeps pointers to everything we pass in here, hence the 'static everywhere
+// an AtomicBool has the same in-memory representation as a bool, so postgres can write to it directly
+pub fn define_bool(name: &'static [u8],
+                   short_desc: &'static [u8],
+                   value: &'static AtomicBool,
+                   context: GucContext) {
+    let (name, short_desc) = (cstr_ptr(name), cstr_ptr(short_desc));
+    error::convert_postgres_error(|| unsafe {
+        DefineCustomBoolVariable(name,
+                                 short_desc,
+                                 ptr::null(),
+                                 value.as_ptr(),
+                                 value.load(Ordering::Relaxed),
+                                 context,
+                                 0,
+                                 ptr::null(),
+                                 ptr::null(),
+                                 ptr::null())
+    })
+}
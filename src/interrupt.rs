@@ -1,5 +1,6 @@
 use std::ptr;
 use error;
+use stdio;
 
 extern "C" {
     static InterruptPending: u8; // C bool
@@ -11,6 +12,7 @@ extern "C" {
 
 #[inline(always)]
 pub fn check_for_interrupts() {
+    stdio::poll();
     unsafe {
         let pending = ptr::read_volatile(&InterruptPending) != 0;
         if pending {
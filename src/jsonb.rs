@@ -0,0 +1,582 @@
+use std::os::raw::{c_char, c_void};
+use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
+use std::ffi::CStr;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::borrow::Cow;
+use std::{mem, ptr, slice};
+
+use varlena::{self, BaseVarlena, Varlena};
+use types::{StaticallyTyped, Oid};
+use numeric::Numeric;
+use alloc::MemoryContext;
+use encoding;
+use error;
+
+// compare utils/jsonb.h
+
+pub struct Jsonb(BaseVarlena);
+impl_varlena!(aligned Jsonb);
+
+unsafe impl StaticallyTyped for Jsonb { const OID: Oid = Oid(3802); }
+
+// JsonbContainer header flags
+const JB_CMASK: u32 = 0x0FFFFFFF;
+const JB_FSCALAR: u32 = 0x10000000;
+const JB_FOBJECT: u32 = 0x20000000;
+const JB_FARRAY: u32 = 0x40000000;
+
+// enum jbvType
+const JBV_NULL: i32 = 0;
+const JBV_STRING: i32 = 1;
+const JBV_NUMERIC: i32 = 2;
+const JBV_BOOL: i32 = 3;
+const JBV_ARRAY: i32 = 0x10;
+const JBV_BINARY: i32 = 0x12;
+
+// enum JsonbIteratorToken
+const WJB_DONE: i32 = 0;
+const WJB_KEY: i32 = 1;
+const WJB_VALUE: i32 = 2;
+const WJB_ELEM: i32 = 3;
+const WJB_BEGIN_ARRAY: i32 = 4;
+const WJB_END_ARRAY: i32 = 5;
+const WJB_BEGIN_OBJECT: i32 = 6;
+const WJB_END_OBJECT: i32 = 7;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawString { len: i32, val: *const c_char }
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawArray { n_elems: i32, elems: *mut RawJsonbValue, raw_scalar: u8 }
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawObject { n_pairs: i32, pairs: *mut c_void }
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawBinary { len: i32, data: *const u32 }
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union RawVal {
+    numeric: *const u8,
+    boolean: u8,
+    string: RawString,
+    array: RawArray,
+    object: RawObject,
+    binary: RawBinary,
+    _datetime: [u64; 3], // only exists on 13+ but it doesn't change the size
+}
+
+// struct JsonbValue
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawJsonbValue {
+    typ: i32,
+    val: RawVal,
+}
+
+extern "C" {
+    fn JsonbIteratorInit(container: *const u32) -> *mut c_void;
+    fn JsonbIteratorNext(it: *mut *mut c_void, val: *mut RawJsonbValue, skip_nested: u8) -> i32;
+    fn findJsonbValueFromContainer(container: *const u32, flags: u32, key: *mut RawJsonbValue) -> *mut RawJsonbValue;
+    fn getIthJsonbValueFromContainer(container: *const u32, i: u32) -> *mut RawJsonbValue;
+    fn pushJsonbValue(pstate: *mut *mut c_void, seq: i32, jbval: *const RawJsonbValue) -> *mut RawJsonbValue;
+    fn JsonbValueToJsonb(val: *mut RawJsonbValue) -> *mut u8;
+    fn JsonbToCString(out: *mut c_void, container: *const u32, estimated_len: i32) -> *mut c_char;
+    fn pfree(ptr: *mut c_void);
+}
+
+impl RawJsonbValue {
+    // s must be in the database encoding
+    fn string(s: &[u8]) -> RawJsonbValue {
+        RawJsonbValue {
+            typ: JBV_STRING,
+            val: RawVal { string: RawString { len: s.len() as i32, val: s.as_ptr() as *const c_char } },
+        }
+    }
+
+    // stored in the database encoding, like text
+    unsafe fn to_str<'a>(&self) -> Cow<'a, str> {
+        let bytes = slice::from_raw_parts(self.val.string.val as *const u8, self.val.string.len as usize);
+        encoding::server_to_utf8(bytes).unwrap_or_else(|e| e.raise())
+    }
+
+    // scalars and jbvBinary only, which is all the iterator and the lookup functions return
+    unsafe fn to_item<'a>(&self) -> Item<'a> {
+        match self.typ {
+            JBV_NULL => Item::Null,
+            JBV_STRING => Item::String(self.to_str()),
+            JBV_NUMERIC => Item::Numeric(Numeric::dst_ptrcast(self.val.numeric)),
+            JBV_BOOL => Item::Bool(self.val.boolean != 0),
+            JBV_BINARY => Item::Container(Container::from_ptr(self.val.binary.data)),
+            other => panic!("unexpected jsonb value type {}", other),
+        }
+    }
+}
+
+// consumes the palloc'd result of one of the lookup functions
+unsafe fn take_item<'a>(v: *mut RawJsonbValue) -> Option<Item<'a>> {
+    if v.is_null() {
+        return None;
+    }
+    let item = (*v).to_item();
+    pfree(v as *mut c_void);
+    Some(item)
+}
+
+impl Jsonb {
+    // Toasted::detoast_packed takes care of the 4-byte header
+    pub fn root(&self) -> Container {
+        unsafe {
+            let ptr = self as *const _ as *const u8;
+            assert!(varlena::is_4b_uncompressed(ptr), "jsonb must be detoasted with detoast_unpacked");
+            Container::from_ptr(ptr.offset(4) as *const u32)
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Item> {
+        self.root().get(key)
+    }
+
+    pub fn tokens(&self) -> Tokens {
+        self.root().tokens()
+    }
+}
+
+impl Display for Jsonb {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        Display::fmt(&self.root(), fmt)
+    }
+}
+impl Debug for Jsonb {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "Jsonb({})", self.root())
+    }
+}
+
+// a (possibly nested) object or array inside a jsonb value
+// top-level scalars are stored as a one-element array with the scalar flag set
+#[derive(Clone, Copy)]
+pub struct Container<'a> {
+    ptr: *const u32,
+    marker: PhantomData<&'a Jsonb>,
+}
+
+impl<'a> Container<'a> {
+    unsafe fn from_ptr(ptr: *const u32) -> Container<'a> {
+        Container { ptr, marker: PhantomData }
+    }
+
+    fn header(&self) -> u32 {
+        unsafe { *self.ptr }
+    }
+
+    pub fn is_object(&self) -> bool { self.header() & JB_FOBJECT != 0 }
+    pub fn is_array(&self) -> bool { self.header() & JB_FARRAY != 0 && !self.is_scalar() }
+    pub fn is_scalar(&self) -> bool { self.header() & JB_FSCALAR != 0 }
+
+    // number of pairs or elements
+    pub fn len(&self) -> usize { (self.header() & JB_CMASK) as usize }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    // None for scalar containers
+    pub fn get(&self, key: &str) -> Option<Item<'a>> {
+        if !self.is_object() {
+            return None;
+        }
+        let ptr = self.ptr;
+        encoding::with_server_encoding(key, |key| {
+            let mut key = RawJsonbValue::string(key);
+            let key = &mut key as *mut RawJsonbValue;
+            unsafe {
+                error::convert_postgres_error(|| take_item(findJsonbValueFromContainer(ptr, JB_FOBJECT, key)))
+            }
+        })
+    }
+
+    // array elements, or the value of a scalar container
+    pub fn get_index(&self, i: usize) -> Option<Item<'a>> {
+        if self.header() & JB_FARRAY == 0 || i >= self.len() {
+            return None;
+        }
+        let ptr = self.ptr;
+        unsafe {
+            error::convert_postgres_error(|| take_item(getIthJsonbValueFromContainer(ptr, i as u32)))
+        }
+    }
+
+    // the scalar stored in a scalar container
+    pub fn scalar(&self) -> Option<Item<'a>> {
+        if self.is_scalar() { self.get_index(0) } else { None }
+    }
+
+    // walks the entire tree, nested containers show up as Begin*/End* tokens
+    pub fn tokens(&self) -> Tokens<'a> {
+        Tokens::new(self.ptr, false)
+    }
+
+    // nested containers show up as Item::Container
+    pub fn elements(&self) -> Elements<'a> {
+        Elements(Tokens::new(self.ptr, true))
+    }
+
+    pub fn entries(&self) -> Entries<'a> {
+        Entries(Tokens::new(self.ptr, true))
+    }
+}
+
+impl<'a> Display for Container<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let ptr = self.ptr;
+        unsafe {
+            let cstr = error::convert_postgres_error(|| JsonbToCString(ptr::null_mut(), ptr, 0));
+            let ret = fmt.write_str(&CStr::from_ptr(cstr).to_string_lossy());
+            pfree(cstr as *mut c_void);
+            ret
+        }
+    }
+}
+impl<'a> Debug for Container<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        Display::fmt(self, fmt)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Item<'a> {
+    Null,
+    Bool(bool),
+    Numeric(&'a Numeric),
+    String(Cow<'a, str>),
+    Container(Container<'a>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Token<'a> {
+    BeginArray { len: usize, raw_scalar: bool },
+    EndArray,
+    BeginObject { len: usize },
+    EndObject,
+    Key(Cow<'a, str>),
+    Value(Item<'a>),
+    Elem(Item<'a>),
+}
+
+// the iterator state is palloc'd in the current memory context
+pub struct Tokens<'a> {
+    it: *mut c_void,
+    skip_nested: bool,
+    marker: PhantomData<&'a Jsonb>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(container: *const u32, skip_nested: bool) -> Tokens<'a> {
+        unsafe {
+            Tokens {
+                it: error::convert_postgres_error(|| JsonbIteratorInit(container)),
+                skip_nested,
+                marker: PhantomData,
+            }
+        }
+    }
+
+    fn next_raw(&mut self) -> (i32, RawJsonbValue) {
+        unsafe {
+            let mut val: RawJsonbValue = mem::zeroed();
+            let (it, valp, skip_nested) = (&mut self.it as *mut _, &mut val as *mut _, self.skip_nested as u8);
+            let token = error::convert_postgres_error(|| JsonbIteratorNext(it, valp, skip_nested));
+            (token, val)
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        if self.it.is_null() {
+            return None;
+        }
+        let (token, val) = self.next_raw();
+        unsafe {
+            Some(match token {
+                WJB_DONE => return None, // the iterator has freed itself
+                WJB_KEY => Token::Key(val.to_str()),
+                WJB_VALUE => Token::Value(val.to_item()),
+                WJB_ELEM => Token::Elem(val.to_item()),
+                WJB_BEGIN_ARRAY => Token::BeginArray {
+                    len: val.val.array.n_elems as usize,
+                    raw_scalar: val.val.array.raw_scalar != 0,
+                },
+                WJB_END_ARRAY => Token::EndArray,
+                WJB_BEGIN_OBJECT => Token::BeginObject { len: val.val.object.n_pairs as usize },
+                WJB_END_OBJECT => Token::EndObject,
+                other => panic!("unexpected jsonb iterator token {}", other),
+            })
+        }
+    }
+}
+
+pub struct Elements<'a>(Tokens<'a>);
+impl<'a> Iterator for Elements<'a> {
+    type Item = Item<'a>;
+
+    fn next(&mut self) -> Option<Item<'a>> {
+        loop {
+            match self.0.next()? {
+                Token::Elem(item) => return Some(item),
+                _ => (),
+            }
+        }
+    }
+}
+
+pub struct Entries<'a>(Tokens<'a>);
+impl<'a> Iterator for Entries<'a> {
+    type Item = (Cow<'a, str>, Item<'a>);
+
+    fn next(&mut self) -> Option<(Cow<'a, str>, Item<'a>)> {
+        loop {
+            if let Token::Key(key) = self.0.next()? {
+                match self.0.next()? {
+                    Token::Value(value) => return Some((key, value)),
+                    other => panic!("jsonb key followed by {:?}", other),
+                }
+            }
+        }
+    }
+}
+
+
+fn server_string(s: &str, strings: &mut Vec<Vec<u8>>) -> RawJsonbValue {
+    strings.push(encoding::with_server_encoding(s, |bytes| bytes.to_vec()));
+    RawJsonbValue::string(strings.last().unwrap())
+}
+
+// a jsonb value to be built from Rust
+#[derive(Debug, Clone)]
+pub enum JsonbValue<'a> {
+    Null,
+    Bool(bool),
+    Numeric(&'a Numeric),
+    String(String),
+    Array(Vec<JsonbValue<'a>>),
+    Object(Vec<(String, JsonbValue<'a>)>),
+    // copies (part of) an existing value
+    Container(Container<'a>),
+}
+
+impl<'a> JsonbValue<'a> {
+    fn is_scalar(&self) -> bool {
+        match *self {
+            JsonbValue::Array(_) | JsonbValue::Object(_) => false,
+            JsonbValue::Container(c) => c.is_scalar(),
+            _ => true,
+        }
+    }
+
+    // token is WJB_ELEM or WJB_VALUE, i.e. where this value goes if it's a scalar
+    // the strings are converted to the database encoding and kept in strings until JsonbValueToJsonb has copied them
+    unsafe fn push(&self, state: *mut *mut c_void, token: i32, strings: &mut Vec<Vec<u8>>) -> *mut RawJsonbValue {
+        let scalar = match *self {
+            JsonbValue::Null => RawJsonbValue { typ: JBV_NULL, val: mem::zeroed() },
+            JsonbValue::Bool(b) => RawJsonbValue { typ: JBV_BOOL, val: RawVal { boolean: b as u8 } },
+            JsonbValue::Numeric(n) => RawJsonbValue { typ: JBV_NUMERIC, val: RawVal { numeric: n as *const _ as *const u8 } },
+            JsonbValue::String(ref s) => server_string(s, strings),
+            JsonbValue::Array(ref elems) => {
+                pushJsonbValue(state, WJB_BEGIN_ARRAY, ptr::null());
+                for elem in elems {
+                    elem.push(state, WJB_ELEM, strings);
+                }
+                return pushJsonbValue(state, WJB_END_ARRAY, ptr::null());
+            }
+            JsonbValue::Object(ref pairs) => {
+                pushJsonbValue(state, WJB_BEGIN_OBJECT, ptr::null());
+                for &(ref key, ref value) in pairs {
+                    pushJsonbValue(state, WJB_KEY, &server_string(key, strings));
+                    value.push(state, WJB_VALUE, strings);
+                }
+                return pushJsonbValue(state, WJB_END_OBJECT, ptr::null());
+            }
+            JsonbValue::Container(c) if c.is_scalar() => {
+                let v = getIthJsonbValueFromContainer(c.ptr, 0);
+                let ret = pushJsonbValue(state, token, v);
+                pfree(v as *mut c_void);
+                return ret;
+            }
+            JsonbValue::Container(c) => {
+                // replay the container token by token (older versions can't push jbvBinary)
+                let mut tokens = c.tokens();
+                let mut ret = ptr::null_mut();
+                while !tokens.it.is_null() {
+                    let (token, val) = tokens.next_raw();
+                    ret = match token {
+                        WJB_DONE => break,
+                        WJB_KEY | WJB_VALUE | WJB_ELEM => pushJsonbValue(state, token, &val),
+                        _ => pushJsonbValue(state, token, ptr::null()),
+                    };
+                }
+                return ret;
+            }
+        };
+        pushJsonbValue(state, token, &scalar)
+    }
+
+    pub fn to_jsonb<'b, 'c>(&self, alloc: &'b MemoryContext<'c>) -> &'b Jsonb {
+        unsafe {
+            alloc.set_current();
+            let this = AssertUnwindSafe(self);
+            let mut strings = AssertUnwindSafe(Vec::new());
+            error::convert_postgres_error(move || {
+                let mut state = ptr::null_mut();
+                let res = if this.is_scalar() {
+                    let wrapper = RawJsonbValue {
+                        typ: JBV_ARRAY,
+                        val: RawVal { array: RawArray { n_elems: 1, elems: ptr::null_mut(), raw_scalar: 1 } },
+                    };
+                    pushJsonbValue(&mut state, WJB_BEGIN_ARRAY, &wrapper);
+                    this.push(&mut state, WJB_ELEM, &mut strings);
+                    pushJsonbValue(&mut state, WJB_END_ARRAY, ptr::null())
+                } else {
+                    this.push(&mut state, WJB_VALUE, &mut strings)
+                };
+                Jsonb::dst_ptrcast(JsonbValueToJsonb(res))
+            })
+        }
+    }
+}
+
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    extern crate serde;
+
+    use std::fmt::{self, Display, Formatter};
+    use std::error::Error;
+    use self::serde::de::{self, Deserialize, Deserializer, Visitor, MapAccess, SeqAccess, DeserializeSeed, IntoDeserializer};
+    use self::serde::de::value::{BorrowedStrDeserializer, StringDeserializer};
+    use std::borrow::Cow;
+    use super::{Jsonb, Item, Elements, Entries};
+
+    #[derive(Debug)]
+    pub struct DeError(String);
+
+    impl Display for DeError {
+        fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+            fmt.write_str(&self.0)
+        }
+    }
+    impl Error for DeError {}
+    impl de::Error for DeError {
+        fn custom<T: Display>(msg: T) -> DeError {
+            DeError(msg.to_string())
+        }
+    }
+
+    impl Jsonb {
+        pub fn deserialize<'a, T: Deserialize<'a>>(&'a self) -> Result<T, DeError> {
+            let root = self.root();
+            root.scalar().unwrap_or(Item::Container(root)).deserialize_into()
+        }
+    }
+
+    impl<'a> Item<'a> {
+        pub fn deserialize_into<T: Deserialize<'a>>(self) -> Result<T, DeError> {
+            T::deserialize(self)
+        }
+    }
+
+    impl<'de> Deserializer<'de> for Item<'de> {
+        type Error = DeError;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            match self {
+                Item::Null => visitor.visit_unit(),
+                Item::Bool(b) => visitor.visit_bool(b),
+                Item::String(Cow::Borrowed(s)) => visitor.visit_borrowed_str(s),
+                Item::String(Cow::Owned(s)) => visitor.visit_string(s),
+                Item::Numeric(n) => {
+                    let s = n.to_string();
+                    if let Ok(i) = s.parse() {
+                        visitor.visit_i64(i)
+                    } else if let Ok(u) = s.parse() {
+                        visitor.visit_u64(u)
+                    } else {
+                        visitor.visit_f64(n.to_f64())
+                    }
+                }
+                Item::Container(c) => match c.scalar() {
+                    Some(scalar) => scalar.deserialize_any(visitor),
+                    None if c.is_object() => visitor.visit_map(MapDe { entries: c.entries(), value: None }),
+                    None => visitor.visit_seq(SeqDe(c.elements())),
+                },
+            }
+        }
+
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+            match self {
+                Item::Null => visitor.visit_none(),
+                other => visitor.visit_some(other),
+            }
+        }
+
+        fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, DeError> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        // only unit variants, spelled as strings
+        fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, DeError> {
+            match self {
+                Item::String(Cow::Borrowed(s)) => visitor.visit_enum(s.into_deserializer()),
+                Item::String(Cow::Owned(s)) => visitor.visit_enum(s.into_deserializer()),
+                _ => Err(de::Error::custom("enums must be stored as strings")),
+            }
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+        }
+    }
+
+    struct SeqDe<'de>(Elements<'de>);
+    impl<'de> SeqAccess<'de> for SeqDe<'de> {
+        type Error = DeError;
+
+        fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, DeError> {
+            match self.0.next() {
+                Some(item) => seed.deserialize(item).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+
+    struct MapDe<'de> {
+        entries: Entries<'de>,
+        value: Option<Item<'de>>,
+    }
+    impl<'de> MapAccess<'de> for MapDe<'de> {
+        type Error = DeError;
+
+        fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, DeError> {
+            match self.entries.next() {
+                Some((key, value)) => {
+                    self.value = Some(value);
+                    match key {
+                        Cow::Borrowed(key) => seed.deserialize(BorrowedStrDeserializer::new(key)).map(Some),
+                        Cow::Owned(key) => seed.deserialize(StringDeserializer::new(key)).map(Some),
+                    }
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+            seed.deserialize(self.value.take().expect("next_value called before next_key"))
+        }
+    }
+}
+#[cfg(feature = "serde")]
+pub use self::serde_impls::DeError;
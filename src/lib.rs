@@ -2,6 +2,7 @@
 
 
 use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use types::StaticallyTyped;
 
@@ -12,13 +13,30 @@ include!(concat!(env!("OUT_DIR"), "/basedefs.rs"));
 pub mod alloc;
 #[macro_use] pub mod varlena;
 pub mod types;
+pub mod expanded;
+pub mod array;
+pub mod range;
+pub mod datetime;
+pub mod numeric;
+pub mod jsonb;
+pub mod net;
+pub mod stringinfo;
 #[macro_use] pub mod export;
+#[macro_use] pub mod basetype;
+pub mod opclass;
+pub mod sortsupport;
+#[macro_use] pub mod enums;
 pub mod catalog;
 mod relation;
 pub mod access;
 pub mod interrupt;
 pub mod tuple;
 pub mod spi;
+pub mod guc;
+pub mod sqlstate;
+pub mod encoding;
+pub mod stdio;
+pub mod log_hook;
 
 // macro-internal modules
 #[doc(hidden)] pub mod magic;
@@ -48,6 +66,24 @@ impl<'a> Debug for Datum<'a> {
 // TODO: SRF (with generators)
 
 
+// only read at load time
+static CAPTURE_STDIO: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn _PG_init() {
+    error::convert_rust_panic(|| {
+        error::init();
+        guc::define_bool(b"rust.capture_stdio\0",
+                         b"Forward stdout/stderr output of Rust functions to the server log.\0",
+                         &CAPTURE_STDIO,
+                         guc::GucContext::Backend);
+        if CAPTURE_STDIO.load(Ordering::Relaxed) {
+            stdio::redirect(error::Level::Log);
+        }
+    })
+}
+
+
 lowlevel_export! {
     fn lowlevel @ pg_finfo_lowlevel(_fcinfo) {
         Datum::create(0)
@@ -101,6 +137,210 @@ CREATE_FUNCTION! {
     }
 }
 
+CREATE_STRICT_FUNCTION! {
+    fn rbitset_test @ pg_finfo_rbitset_test (ctx, b: bytea, i: int4) -> bool {
+        if i < 0 { return Some(false); }
+
+        // only fetch the byte we're interested in
+        let byte = b.slice((i / 8) as usize, 1, ctx.allocator());
+        Some(byte.first().map(|&byte| byte & (1 << (i % 8)) != 0).unwrap_or(false))
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn toast_info @ pg_finfo_toast_info (ctx, b: bytea) -> text {
+        Some(ctx.alloc_text(&format!("{:?}", b.storage())).into())
+    }
+}
+
+// an aggregate transition function that keeps its state expanded instead of copying it on every row
+// CREATE AGGREGATE rbitset_or_agg(bytea) (SFUNC = rbitset_or, STYPE = bytea)
+struct ByteSet(Vec<u8>);
+
+impl expanded::ExpandedObject for ByteSet {
+    type Flat = types::bytea;
+
+    fn flat_size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn flatten_into(&self, payload: &mut [u8]) {
+        payload.copy_from_slice(&self.0)
+    }
+}
+
+CREATE_FUNCTION! {
+    fn rbitset_or @ pg_finfo_rbitset_or (ctx, acc: bytea, b: bytea) -> bytea {
+        use expanded::Expanded;
+
+        let b = b?.detoast_packed(ctx.allocator());
+        // the state has to live in the aggregate context to stay expanded between calls
+        let parent = ctx.aggregate_allocator().unwrap_or(ctx.result_allocator());
+        let mut acc = match acc.as_ref().and_then(|acc| acc.expanded::<ByteSet>()) {
+            // we own read/write objects and may modify them in place, which is what happens from the second row on
+            Some(e) => if e.is_read_write() { e } else { Expanded::new(ByteSet(e.get().0.clone()), parent) },
+            None => {
+                let flat = acc.map(|acc| acc.detoast_packed(ctx.allocator()).to_vec()).unwrap_or_default();
+                Expanded::new(ByteSet(flat), parent)
+            }
+        };
+
+        {
+            let set = &mut acc.get_mut().unwrap().0;
+            if set.len() < b.len() {
+                set.resize(b.len(), 0);
+            }
+            for (x, y) in set.iter_mut().zip(b.iter()) {
+                *x |= *y;
+            }
+        }
+        Some(acc.into())
+    }
+}
+
+// the same bitset as a proper type, e.g. '{1,5,7}'::rbitset(8)
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct RBitSet(Vec<u8>);
+
+impl basetype::BaseType for RBitSet {
+    const BINARY_IO: bool = true;
+    const TYPMOD: bool = true;
+
+    fn from_payload(payload: &[u8]) -> RBitSet {
+        RBitSet(payload.to_vec())
+    }
+
+    fn to_payload(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn input(s: &str, typmod: i32) -> RBitSet {
+        use sqlstate::SqlState;
+
+        let s = s.trim();
+        if !s.starts_with('{') || !s.ends_with('}') {
+            error::raise(SqlState::InvalidTextRepresentation, format!("malformed rbitset literal: \"{}\"", s));
+        }
+        let mut bytes = Vec::new();
+        for elem in s[1..s.len() - 1].split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let bit: u32 = elem.parse().unwrap_or_else(|_|
+                error::raise(SqlState::InvalidTextRepresentation, format!("invalid rbitset element: \"{}\"", elem)));
+            if typmod >= 0 && bit >= typmod as u32 {
+                error::raise(SqlState::NumericValueOutOfRange, format!("bit {} does not fit in rbitset({})", bit, typmod));
+            }
+            let index = (bit / 8) as usize;
+            if bytes.len() <= index {
+                bytes.resize(index + 1, 0);
+            }
+            bytes[index] |= 1 << (bit % 8);
+        }
+        RBitSet(bytes)
+    }
+
+    fn output(&self) -> String {
+        let bits: Vec<String> = (0..self.0.len() * 8).filter(|&i| self.0[i / 8] & (1 << (i % 8)) != 0).map(|i| i.to_string()).collect();
+        format!("{{{}}}", bits.join(","))
+    }
+
+    // the payload is just bytes, so it's platform independent already
+    // trailing zeros are dropped so that equal sets compare equal
+    fn receive(data: &[u8], _typmod: i32) -> RBitSet {
+        let len = data.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        RBitSet(data[..len].to_vec())
+    }
+
+    fn send(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+
+    fn typmod_in(mods: &[i32]) -> i32 {
+        match *mods {
+            [bits] if bits > 0 => bits,
+            _ => error::raise(::sqlstate::SqlState::InvalidParameterValue, "rbitset takes a single positive number of bits"),
+        }
+    }
+}
+
+CREATE_BASE_TYPE! {
+    type rbitset = RBitSet {
+        input: rbitset_in @ pg_finfo_rbitset_in,
+        output: rbitset_out @ pg_finfo_rbitset_out,
+        receive: rbitset_recv @ pg_finfo_rbitset_recv,
+        send: rbitset_send @ pg_finfo_rbitset_send,
+        typmod_in: rbitset_typmod_in @ pg_finfo_rbitset_typmod_in,
+        typmod_out: rbitset_typmod_out @ pg_finfo_rbitset_typmod_out,
+    }
+}
+
+CREATE_OPERATORS! {
+    type rbitset = RBitSet {
+        cmp: rbitset_cmp @ pg_finfo_rbitset_cmp,
+        eq: rbitset_eq @ pg_finfo_rbitset_eq,
+        ne: rbitset_ne @ pg_finfo_rbitset_ne,
+        lt: rbitset_lt @ pg_finfo_rbitset_lt,
+        le: rbitset_le @ pg_finfo_rbitset_le,
+        gt: rbitset_gt @ pg_finfo_rbitset_gt,
+        ge: rbitset_ge @ pg_finfo_rbitset_ge,
+        hash: rbitset_hash @ pg_finfo_rbitset_hash,
+    }
+}
+
+impl sortsupport::SortSupport for RBitSet {
+    const ABBREVIATE: bool = true;
+
+    // the leading bytes, big-endian so that the keys compare like the vectors do
+    fn abbreviate(&self) -> usize {
+        let mut key = [0u8; mem::size_of::<usize>()];
+        let len = self.0.len().min(key.len());
+        key[..len].copy_from_slice(&self.0[..len]);
+        usize::from_be_bytes(key)
+    }
+}
+
+CREATE_SORT_SUPPORT! {
+    type rbitset = RBitSet {
+        sortsupport: rbitset_sortsupport @ pg_finfo_rbitset_sortsupport,
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn int4range_len @ pg_finfo_int4range_len (ctx, r: int4range) -> int4 {
+        use std::ops::Bound;
+
+        // int4range is canonicalized to [lower,upper)
+        match r.detoast_unpacked(ctx.allocator()).bounds() {
+            None => Some(0),
+            Some((Bound::Included(lower), Bound::Excluded(upper))) => Some(upper - lower),
+            Some(_) => None,
+        }
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn int4range_upto @ pg_finfo_int4range_upto (ctx, upper: int4) -> int4range {
+        Some(range::Range::from_bounds(..=upper, ctx.result_allocator()).into())
+    }
+}
+
+// CREATE TYPE weekday AS ENUM ('mon', 'tue', 'wed', 'thu', 'fri', 'sat', 'sun');
+PG_ENUM! {
+    pub enum Weekday { Mon = "mon", Tue = "tue", Wed = "wed", Thu = "thu", Fri = "fri", Sat = "sat", Sun = "sun" }
+}
+
+// CREATE FUNCTION next_weekday(anyenum) RETURNS anyenum
+CREATE_STRICT_FUNCTION! {
+    fn next_weekday @ pg_finfo_next_weekday (ctx, day: anyenum) -> anyenum {
+        let next = match day.get::<Weekday>()? {
+            Weekday::Mon => Weekday::Tue,
+            Weekday::Tue => Weekday::Wed,
+            Weekday::Wed => Weekday::Thu,
+            Weekday::Thu => Weekday::Fri,
+            Weekday::Fri | Weekday::Sat | Weekday::Sun => Weekday::Mon,
+        };
+        Some(enums::EnumValue::new(next, ctx.fcinfo().return_type()))
+    }
+}
+
 CREATE_FUNCTION! {
     fn rbitand_count @ pg_finfo_rbitand_count (ctx, a: bytea, b: bytea) -> int4 {
         let a = a?.detoast_packed(ctx.allocator());
@@ -111,6 +351,13 @@ CREATE_FUNCTION! {
     }
 }
 
+CREATE_STRICT_FUNCTION! {
+    fn smallsum @ pg_finfo_smallsum (_ctx, a: int2, b: char, negate: bool) -> int4 {
+        let sum = a as i32 + b as i32;
+        Some(if negate { -sum } else { sum })
+    }
+}
+
 CREATE_FUNCTION! {
     fn errtest @ pg_finfo_errtest (_ctx) -> void {
         error::convert_postgres_error(|| error::convert_rust_panic(|| panic!("inney")))
@@ -132,6 +379,70 @@ CREATE_STRICT_FUNCTION! {
     }
 }
 
+CREATE_STRICT_FUNCTION! {
+    fn int4array_sum @ pg_finfo_int4array_sum (ctx, a: int4array) -> int8 {
+        let a = a.detoast_unpacked(ctx.allocator());
+        Some(match a.as_slice() {
+            Some(values) => values.iter().map(|&x| x as i64).sum(),
+            None => a.iter().map(|x| x.unwrap_or(0) as i64).sum(),
+        })
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn textarray_lengths @ pg_finfo_textarray_lengths (ctx, a: textarray) -> int4array {
+        let a = a.detoast_unpacked(ctx.allocator());
+        let mut lengths = ctx.array_builder();
+        for elem in a.iter() {
+            lengths.push(elem.map(|t: varlena::Toasted<types::text>| t.detoast_packed(ctx.allocator()).to_str().map(|s| s.chars().count() as i32).unwrap_or(-1)));
+        }
+        Some(lengths.finish().into())
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn numeric_scale @ pg_finfo_numeric_scale (ctx, n: numeric, factor: int8) -> numeric {
+        let n = n.detoast_packed(ctx.allocator());
+        let factor = numeric::Numeric::from_i64(factor, ctx.allocator());
+        Some(n.mul(factor, ctx.result_allocator()).into())
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn jsonb_wrap @ pg_finfo_jsonb_wrap (ctx, j: jsonb, key: text) -> jsonb {
+        let j = j.detoast_unpacked(ctx.allocator());
+        let key = key.detoast_packed(ctx.allocator()).to_str()?;
+        let wrapped = jsonb::JsonbValue::Object(vec![(key.into_owned(), jsonb::JsonbValue::Container(j.root()))]);
+        Some(wrapped.to_jsonb(ctx.result_allocator()).into())
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn inet_network @ pg_finfo_inet_network (ctx, i: inet) -> cidr {
+        let i = i.detoast_packed(ctx.allocator());
+        let prefix = i.prefix_len();
+        let network = match i.addr() {
+            std::net::IpAddr::V4(a) => std::net::IpAddr::V4((u32::from(a) & !((0xFFFF_FFFFu64 >> prefix) as u32)).into()),
+            std::net::IpAddr::V6(a) => std::net::IpAddr::V6((u128::from(a) & !(!0u128).checked_shr(prefix as u32).unwrap_or(0)).into()),
+        };
+        Some(net::Cidr::new(network, prefix, ctx.result_allocator()).unwrap().into())
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn bpchar_to_varchar @ pg_finfo_bpchar_to_varchar (ctx, c: bpchar) -> varchar {
+        let c = c.detoast_packed(ctx.allocator());
+        let typmod = ctx.fcinfo().arg_typmod(0);
+        Some(types::varchar::new(&c.to_str_trimmed()?, typmod, ctx.result_allocator()).into())
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn namesuffix @ pg_finfo_namesuffix (ctx, n: name) -> name {
+        let suffixed = format!("{}_rs", n.to_str()?);
+        Some(&*ctx.alloc_name(&suffixed))
+    }
+}
 
 CREATE_STRICT_FUNCTION! {
     fn spass @ pg_finfo_spass (_ctx, a: text) -> text {
@@ -139,6 +450,26 @@ CREATE_STRICT_FUNCTION! {
     }
 }
 
+CREATE_STRICT_FUNCTION! {
+    fn srepeat @ pg_finfo_srepeat (ctx, a: text, n: int4) -> text {
+        use std::fmt::Write;
+
+        let a = a.detoast_packed(ctx.allocator()).to_str()?;
+        let mut out = ctx.text_writer();
+        for i in 0..n {
+            write!(out, "{}{}", if i > 0 { ", " } else { "" }, a).unwrap();
+        }
+        Some(out.finish().into())
+    }
+}
+
+CREATE_STRICT_FUNCTION! {
+    fn supper @ pg_finfo_supper (ctx, a: text) -> text {
+        let a = a.detoast_packed(ctx.allocator()).to_str()?;
+        Some(ctx.alloc_text(&a.to_uppercase()).into())
+    }
+}
+
 CREATE_STRICT_FUNCTION! {
     fn ptext @ pg_finfo_ptext (ctx, a: text, b: text) -> int4 {
 //        let a = a.to_varlena()?;
@@ -168,7 +499,7 @@ CREATE_STRICT_FUNCTION! {
     fn scanindex @ pg_finfo_scanindex(ctx, heap: oid, index: oid, col: int4, val: int4) -> int4 {
         let heap = access::heap::Heap::open(heap);
         let index = access::index::Index::open(index);
-        let keys = [access::index::ScanKey::new(col as u16, val as usize)];
+        let keys = [access::index::ScanKey::new(&index, col as u16, access::index::Strategy::Equal, val as usize)];
         let mut scan = index.scan(&heap, &keys, ctx.allocator());
 
         while let Some(x) = scan.next() {
@@ -194,7 +525,7 @@ CREATE_STRICT_FUNCTION! {
         let sql = sql.detoast_packed(ctx.allocator()).to_str()?;
 
         let spi = ctx.connect_spi();
-        let res = spi.execute(sql, &[42i32.into(), ::spi::Parameter::null::<i32>(), 1337i32.into()]).unwrap();
+        let res = spi.execute(&sql, &[42i32.into(), ::spi::Parameter::null::<i32>(), 1337i32.into()]).unwrap();
         println!("{:?}", res);
 
         Some(42)
@@ -206,7 +537,7 @@ CREATE_STRICT_FUNCTION! {
         let sql = sql.detoast_packed(ctx.allocator()).to_str()?;
 
         let spi = ctx.connect_spi();
-        let mut cursor = spi.execute_cursor(sql, &[42i32.into(), ::spi::Parameter::null::<i32>(), 1337i32.into()]);
+        let mut cursor = spi.execute_cursor(&sql, &[42i32.into(), ::spi::Parameter::null::<i32>(), 1337i32.into()]);
         println!("{:?}", cursor.fetch(::spi::Direction::Forward, 2));
         cursor.move_relative(1);
         println!("{:?}", cursor.fetch(::spi::Direction::Forward, 1));
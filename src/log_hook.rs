@@ -0,0 +1,59 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use error::ErrorData;
+
+// a rust emit_log_hook: gets to look at every message before it is written to the server log.
+// it may suppress (set_output_to_server(false)) or rewrite messages or forward them elsewhere.
+//
+// NB: don't call elog from inside the hook, those messages are not passed to it again
+
+type EmitLogHook = unsafe extern "C" fn(edata: *mut ErrorData);
+
+extern "C" {
+    static mut emit_log_hook: Option<EmitLogHook>;
+}
+
+// postgres is single-threaded software.
+static mut PREV_HOOK: Option<EmitLogHook> = None;
+static mut USER_HOOK: Option<fn(&mut ErrorData)> = None;
+static IN_HOOK: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" fn hook_trampoline(edata: *mut ErrorData) {
+    if let Some(user_hook) = USER_HOOK {
+        // guard against recursion (e.g. the hook itself causing a log message)
+        if !IN_HOOK.swap(true, Ordering::Relaxed) {
+            // must never unwind into C, and there is no way to report anything from here
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| user_hook(&mut *edata)));
+            IN_HOOK.store(false, Ordering::Relaxed);
+        }
+    }
+
+    if let Some(prev) = PREV_HOOK {
+        prev(edata);
+    }
+}
+
+// to be called from _PG_init
+pub fn install(hook: fn(&mut ErrorData)) {
+    unsafe {
+        let current = USER_HOOK;
+        assert!(current.is_none(), "emit_log_hook is already installed");
+
+        USER_HOOK = Some(hook);
+        PREV_HOOK = emit_log_hook;
+        emit_log_hook = Some(hook_trampoline);
+    }
+}
+
+// in theory we would have to call this from _PG_fini
+pub fn uninstall() {
+    unsafe {
+        let current = USER_HOOK;
+        if current.is_some() {
+            emit_log_hook = PREV_HOOK;
+            USER_HOOK = None;
+            PREV_HOOK = None;
+        }
+    }
+}
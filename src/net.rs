@@ -0,0 +1,169 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::fmt::{self, Debug, Display, Formatter};
+
+use Datum;
+use varlena::{self, BaseVarlena, Varlena};
+use types::{FromDatum, StaticallyTyped, Oid, palloc_datum};
+use alloc::MemoryContext;
+
+// compare utils/inet.h
+
+// PGSQL_AF_INET and PGSQL_AF_INET6, independent of the platform's AF_* values
+const PGSQL_AF_INET: u8 = 2;
+const PGSQL_AF_INET6: u8 = 3;
+
+// inet_struct without the ipaddr padding: only as many address bytes as the family needs are stored
+unsafe fn read_inet(ptr: *const u8) -> (IpAddr, u8) {
+    let data = varlena::payload(ptr);
+    let (family, bits, addr) = (data[0], data[1], &data[2..]);
+    let addr = match family {
+        PGSQL_AF_INET => IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3])),
+        PGSQL_AF_INET6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        other => panic!("invalid inet family {}", other),
+    };
+    (addr, bits)
+}
+
+fn max_bits(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+fn write_inet<'b, 'c>(addr: IpAddr, bits: u8, alloc: &'b MemoryContext<'c>) -> *const u8 {
+    let family = match addr {
+        IpAddr::V4(_) => PGSQL_AF_INET,
+        IpAddr::V6(_) => PGSQL_AF_INET6,
+    };
+    let octets = octets(&addr);
+
+    let size = 4 + 2 + octets.len();
+    let buf = alloc.alloc(size);
+    buf[4] = family;
+    buf[5] = bits;
+    buf[6..].copy_from_slice(&octets);
+    unsafe { varlena::set_varsize(buf.as_mut_ptr(), size); }
+    buf.as_ptr()
+}
+
+// an address with an optional netmask, e.g. 192.168.0.1/24
+pub struct Inet(BaseVarlena);
+impl_varlena!(Inet);
+
+// a network, e.g. 192.168.0.0/24 (no bits may be set to the right of the mask)
+pub struct Cidr(BaseVarlena);
+impl_varlena!(Cidr);
+
+impl Inet {
+    // None if the prefix is longer than the address
+    pub fn new<'b, 'c>(addr: IpAddr, prefix_len: u8, alloc: &'b MemoryContext<'c>) -> Option<&'b Inet> {
+        if prefix_len > max_bits(&addr) {
+            return None;
+        }
+        unsafe { Some(Inet::dst_ptrcast(write_inet(addr, prefix_len, alloc))) }
+    }
+
+    // the value must have been detoasted (packed is fine)
+    pub fn addr(&self) -> IpAddr {
+        unsafe { read_inet(self as *const _ as *const u8).0 }
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        unsafe { read_inet(self as *const _ as *const u8).1 }
+    }
+}
+
+impl Cidr {
+    // None if the prefix is longer than the address or there are host bits set
+    pub fn new<'b, 'c>(addr: IpAddr, prefix_len: u8, alloc: &'b MemoryContext<'c>) -> Option<&'b Cidr> {
+        if prefix_len > max_bits(&addr) {
+            return None;
+        }
+        let octets = octets(&addr);
+        for (i, &byte) in octets.iter().enumerate() {
+            let mask_bits = ::std::cmp::min(8, (prefix_len as usize).saturating_sub(i * 8));
+            let host_mask = (0xFFu16 >> mask_bits) as u8;
+            if byte & host_mask != 0 {
+                return None;
+            }
+        }
+        unsafe { Some(Cidr::dst_ptrcast(write_inet(addr, prefix_len, alloc))) }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        unsafe { read_inet(self as *const _ as *const u8).0 }
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        unsafe { read_inet(self as *const _ as *const u8).1 }
+    }
+}
+
+impl Display for Inet {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let (addr, bits) = unsafe { read_inet(self as *const _ as *const u8) };
+        // same as inet_out: the prefix is only shown if it's not the full address
+        if bits == max_bits(&addr) {
+            write!(fmt, "{}", addr)
+        } else {
+            write!(fmt, "{}/{}", addr, bits)
+        }
+    }
+}
+impl Display for Cidr {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "{}/{}", self.addr(), self.prefix_len())
+    }
+}
+impl Debug for Inet {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result { write!(fmt, "Inet({})", self) }
+}
+impl Debug for Cidr {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result { write!(fmt, "Cidr({})", self) }
+}
+
+// fixed-size but passed by reference
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+// EUI-64, 10+ only
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr8(pub [u8; 8]);
+
+impl Display for MacAddr {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(fmt, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a[0], a[1], a[2], a[3], a[4], a[5])
+    }
+}
+impl Display for MacAddr8 {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(fmt, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7])
+    }
+}
+
+// the byref ones are copied out (and into a palloc'd chunk in the current memory context)
+impl<'a> From<MacAddr> for Datum<'a> { fn from(m: MacAddr) -> Datum<'a> { unsafe { palloc_datum(m) } } }
+impl<'a> FromDatum<'a> for MacAddr { unsafe fn from(d: Datum<'a>) -> MacAddr { *(d.0 as *const MacAddr) } }
+impl<'a> From<MacAddr8> for Datum<'a> { fn from(m: MacAddr8) -> Datum<'a> { unsafe { palloc_datum(m) } } }
+impl<'a> FromDatum<'a> for MacAddr8 { unsafe fn from(d: Datum<'a>) -> MacAddr8 { *(d.0 as *const MacAddr8) } }
+
+unsafe impl StaticallyTyped for Cidr { const OID: Oid = Oid(650); }
+unsafe impl StaticallyTyped for MacAddr8 { const OID: Oid = Oid(774); }
+unsafe impl StaticallyTyped for MacAddr { const OID: Oid = Oid(829); }
+unsafe impl StaticallyTyped for Inet { const OID: Oid = Oid(869); }
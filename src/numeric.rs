@@ -0,0 +1,204 @@
+use std::os::raw::{c_char, c_void};
+use std::cmp::Ordering;
+use std::ffi::{CStr, CString};
+use std::fmt::{self, Debug, Display, Formatter};
+
+use Datum;
+use varlena::{BaseVarlena, Varlena};
+use types::{FromDatum, StaticallyTyped, Oid, float8, int64_from_datum, int64_to_datum};
+use alloc::MemoryContext;
+use error::{self, PgError};
+
+// compare utils/numeric.h
+//
+// the on-disk format is a packed base-10000 representation that we never look into ourselves,
+// everything goes through the builtin functions instead
+
+pub struct Numeric(BaseVarlena);
+impl_varlena!(Numeric);
+
+unsafe impl StaticallyTyped for Numeric { const OID: Oid = Oid(1700); }
+
+type PgFunction = unsafe extern "C" fn(fcinfo: *mut c_void) -> Datum<'static>;
+
+extern "C" {
+    fn DirectFunctionCall1Coll(func: PgFunction, collation: Oid, arg1: Datum) -> Datum<'static>;
+    fn DirectFunctionCall2Coll(func: PgFunction, collation: Oid, arg1: Datum, arg2: Datum) -> Datum<'static>;
+    fn DirectFunctionCall3Coll(func: PgFunction, collation: Oid, arg1: Datum, arg2: Datum, arg3: Datum) -> Datum<'static>;
+    fn pfree(ptr: *mut c_void);
+
+    fn numeric_in(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_out(fcinfo: *mut c_void) -> Datum<'static>;
+    fn int8_numeric(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_int8(fcinfo: *mut c_void) -> Datum<'static>;
+    fn float8_numeric(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_float8(fcinfo: *mut c_void) -> Datum<'static>;
+
+    fn numeric_add(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_sub(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_mul(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_div(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_mod(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_uminus(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_abs(fcinfo: *mut c_void) -> Datum<'static>;
+    fn numeric_cmp(fcinfo: *mut c_void) -> Datum<'static>;
+}
+
+const INVALID_OID: Oid = Oid(0);
+
+// the result is allocated in whatever the current memory context is
+unsafe fn result<'b>(d: Datum) -> &'b Numeric {
+    Numeric::dst_ptrcast(d.0 as *const u8)
+}
+
+// a constant, in the current memory context
+unsafe fn literal<'b>(s: &[u8]) -> &'b Numeric {
+    error::convert_postgres_error(|| result(DirectFunctionCall3Coll(numeric_in, INVALID_OID,
+                                                                    Datum::create(s.as_ptr() as usize),
+                                                                    INVALID_OID.into(),
+                                                                    (-1i32).into())))
+}
+
+impl Numeric {
+    fn datum(&self) -> Datum {
+        Datum::create(self as *const _ as *const u8 as usize)
+    }
+
+    // accepts everything numeric_in does, e.g. "-1.5e3" or "NaN"
+    // errors are returned rather than raised, which takes a subtransaction
+    pub fn parse<'b, 'c>(s: &str, alloc: &'b MemoryContext<'c>) -> Result<&'b Numeric, PgError> {
+        let s = CString::new(s).expect("numeric literal must not contain nul bytes");
+        unsafe {
+            alloc.set_current();
+            error::catch_postgres_error_subxact(|| {
+                result(DirectFunctionCall3Coll(numeric_in, INVALID_OID,
+                                               Datum::create(s.as_ptr() as usize),
+                                               INVALID_OID.into(), // typelem, unused
+                                               (-1i32).into())) // no typmod
+            })
+        }
+    }
+
+    pub fn from_i64<'b, 'c>(value: i64, alloc: &'b MemoryContext<'c>) -> &'b Numeric {
+        unsafe {
+            alloc.set_current();
+            error::convert_postgres_error(|| result(DirectFunctionCall1Coll(int8_numeric, INVALID_OID, int64_to_datum(value))))
+        }
+    }
+
+    // NaN maps to NaN, infinities are an error before 14
+    pub fn from_f64<'b, 'c>(value: f64, alloc: &'b MemoryContext<'c>) -> &'b Numeric {
+        unsafe {
+            alloc.set_current();
+            error::convert_postgres_error(|| result(DirectFunctionCall1Coll(float8_numeric, INVALID_OID, value.into())))
+        }
+    }
+
+    // None for NaN, infinities and values out of range, rounds fractional values
+    pub fn to_i64(&self) -> Option<i64> {
+        unsafe {
+            // numeric_int8 rounds half away from zero, so anything strictly between these fits
+            let lower = literal(b"-9223372036854775808.5\0");
+            let upper = literal(b"9223372036854775807.5\0");
+            // NaN sorts above everything
+            let in_range = lower < self && self < upper;
+            pfree(lower as *const _ as *mut c_void);
+            pfree(upper as *const _ as *mut c_void);
+
+            if in_range {
+                Some(error::convert_postgres_error(|| int64_from_datum(DirectFunctionCall1Coll(numeric_int8, INVALID_OID, self.datum()))))
+            } else {
+                None
+            }
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        unsafe {
+            error::convert_postgres_error(|| <float8 as FromDatum>::from(DirectFunctionCall1Coll(numeric_float8, INVALID_OID, self.datum())))
+        }
+    }
+
+    // numeric_out, also available through Display
+    fn output(&self) -> String {
+        unsafe {
+            let cstr = error::convert_postgres_error(|| DirectFunctionCall1Coll(numeric_out, INVALID_OID, self.datum()).0 as *mut c_char);
+            let ret = CStr::from_ptr(cstr).to_string_lossy().into_owned();
+            pfree(cstr as *mut c_void);
+            ret
+        }
+    }
+
+    fn binary_op<'b, 'c>(&self, func: PgFunction, other: &Numeric, alloc: &'b MemoryContext<'c>) -> &'b Numeric {
+        unsafe {
+            alloc.set_current();
+            error::convert_postgres_error(|| result(DirectFunctionCall2Coll(func, INVALID_OID, self.datum(), other.datum())))
+        }
+    }
+
+    fn unary_op<'b, 'c>(&self, func: PgFunction, alloc: &'b MemoryContext<'c>) -> &'b Numeric {
+        unsafe {
+            alloc.set_current();
+            error::convert_postgres_error(|| result(DirectFunctionCall1Coll(func, INVALID_OID, self.datum())))
+        }
+    }
+
+    pub fn add<'b, 'c>(&self, other: &Numeric, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.binary_op(numeric_add, other, alloc) }
+    pub fn sub<'b, 'c>(&self, other: &Numeric, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.binary_op(numeric_sub, other, alloc) }
+    pub fn mul<'b, 'c>(&self, other: &Numeric, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.binary_op(numeric_mul, other, alloc) }
+    // division by zero raises the usual postgres error (as a panic)
+    pub fn div<'b, 'c>(&self, other: &Numeric, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.binary_op(numeric_div, other, alloc) }
+    pub fn rem<'b, 'c>(&self, other: &Numeric, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.binary_op(numeric_mod, other, alloc) }
+    pub fn neg<'b, 'c>(&self, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.unary_op(numeric_uminus, alloc) }
+    pub fn abs<'b, 'c>(&self, alloc: &'b MemoryContext<'c>) -> &'b Numeric { self.unary_op(numeric_abs, alloc) }
+}
+
+// numeric_cmp is a total order: NaN equals itself and sorts above everything else
+impl Ord for Numeric {
+    fn cmp(&self, other: &Numeric) -> Ordering {
+        let res = unsafe {
+            error::convert_postgres_error(|| DirectFunctionCall2Coll(numeric_cmp, INVALID_OID, self.datum(), other.datum()).0 as i32)
+        };
+        res.cmp(&0)
+    }
+}
+impl PartialOrd for Numeric {
+    fn partial_cmp(&self, other: &Numeric) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl PartialEq for Numeric {
+    fn eq(&self, other: &Numeric) -> bool { self.cmp(other) == Ordering::Equal }
+}
+impl Eq for Numeric {}
+
+impl Display for Numeric {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        fmt.write_str(&self.output())
+    }
+}
+impl Debug for Numeric {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        write!(fmt, "Numeric({})", self.output())
+    }
+}
+
+
+#[cfg(feature = "rust_decimal")]
+mod decimal_impls {
+    extern crate rust_decimal;
+
+    use self::rust_decimal::Decimal;
+    use alloc::MemoryContext;
+    use super::Numeric;
+
+    // these go through the text representation, which is exact in both directions
+    impl Numeric {
+        pub fn from_decimal<'b, 'c>(value: &Decimal, alloc: &'b MemoryContext<'c>) -> &'b Numeric {
+            Numeric::parse(&value.to_string(), alloc).expect("Decimal produced an invalid numeric literal")
+        }
+
+        // None for NaN, infinities and anything that doesn't fit into 96 bits
+        pub fn to_decimal(&self) -> Option<Decimal> {
+            Decimal::from_str_exact(&self.output()).ok()
+        }
+    }
+}
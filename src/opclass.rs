@@ -0,0 +1,174 @@
+use std::cmp::Ordering;
+
+use Datum;
+use varlena::{self, Varlena, Toasted};
+use types::FromDatum;
+use basetype::{self, BaseType};
+use export::FunctionCallContext;
+
+// comparison operators and btree/hash operator classes for types created with CREATE_BASE_TYPE!
+
+#[cfg(hash_bytes)]
+extern "C" {
+    fn hash_bytes(k: *const u8, keylen: i32) -> u32;
+}
+
+#[cfg(not(hash_bytes))]
+extern "C" {
+    fn hash_any(k: *const u8, keylen: i32) -> Datum<'static>;
+}
+
+#[cfg(not(hash_bytes))]
+unsafe fn hash_bytes(k: *const u8, keylen: i32) -> u32 {
+    hash_any(k, keylen).0 as u32
+}
+
+fn load_arg<'a, T: BaseType, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>, n: usize) -> T {
+    let arg: Toasted<V> = unsafe { FromDatum::from(ctx.fcinfo().args_strict()[n]) };
+    basetype::load::<T, V>(arg.detoast_packed(ctx.allocator()))
+}
+
+fn compare<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Ordering {
+    load_arg::<T, V>(ctx, 0).cmp(&load_arg::<T, V>(ctx, 1))
+}
+
+#[doc(hidden)]
+pub fn call_cmp<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let ret: i32 = match compare::<T, V>(ctx) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    };
+    ret.into()
+}
+
+#[doc(hidden)]
+pub fn call_eq<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    (compare::<T, V>(ctx) == Ordering::Equal).into()
+}
+
+#[doc(hidden)]
+pub fn call_ne<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    (compare::<T, V>(ctx) != Ordering::Equal).into()
+}
+
+#[doc(hidden)]
+pub fn call_lt<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    (compare::<T, V>(ctx) == Ordering::Less).into()
+}
+
+#[doc(hidden)]
+pub fn call_le<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    (compare::<T, V>(ctx) != Ordering::Greater).into()
+}
+
+#[doc(hidden)]
+pub fn call_gt<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    (compare::<T, V>(ctx) == Ordering::Greater).into()
+}
+
+#[doc(hidden)]
+pub fn call_ge<'a, T: BaseType + Ord, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    (compare::<T, V>(ctx) != Ordering::Less).into()
+}
+
+// hash indexes and hash partitions are persistent, so neither std's Hash (whose output may change
+// between Rust releases) nor a Rust hasher will do. the stored payload is exactly as stable as it has to be
+#[doc(hidden)]
+pub fn call_hash<'a, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    let arg: Toasted<V> = unsafe { FromDatum::from(ctx.fcinfo().args_strict()[0]) };
+    let payload = unsafe { varlena::payload(arg.detoast_packed(ctx.allocator()) as *const V as *const u8) };
+    (unsafe { hash_bytes(payload.as_ptr(), payload.len() as i32) } as i32).into()
+}
+
+// the C symbols exported by CREATE_OPERATORS!
+#[doc(hidden)]
+pub struct OperatorFunctions {
+    pub cmp: &'static str,
+    pub eq: &'static str,
+    pub ne: &'static str,
+    pub lt: &'static str,
+    pub le: &'static str,
+    pub gt: &'static str,
+    pub ge: &'static str,
+    pub hash: &'static str,
+}
+
+#[doc(hidden)]
+pub fn create_operators_sql(name: &str, f: &OperatorFunctions) -> String {
+    let args = format!("{}, {}", name, name);
+    let mut sql = String::new();
+    sql += &basetype::create_function_sql(f.cmp, &args, "integer");
+    for fname in &[f.eq, f.ne, f.lt, f.le, f.gt, f.ge] {
+        sql += &basetype::create_function_sql(fname, &args, "boolean");
+    }
+    sql += &basetype::create_function_sql(f.hash, name, "integer");
+
+    // FUNCTION, scalarlesel and scalargesel are 11+
+    let (keyword, lesel, lejoinsel, gesel, gejoinsel) = if ::PG_VERSION >= 1100 {
+        ("FUNCTION", "scalarlesel", "scalarlejoinsel", "scalargesel", "scalargejoinsel")
+    } else {
+        ("PROCEDURE", "scalarltsel", "scalarltjoinsel", "scalargtsel", "scalargtjoinsel")
+    };
+    let operators = [
+        ("=", f.eq, "=", "<>", "eqsel", "eqjoinsel", ", HASHES, MERGES"),
+        ("<>", f.ne, "<>", "=", "neqsel", "neqjoinsel", ""),
+        ("<", f.lt, ">", ">=", "scalarltsel", "scalarltjoinsel", ""),
+        ("<=", f.le, ">=", ">", lesel, lejoinsel, ""),
+        (">", f.gt, "<", "<=", "scalargtsel", "scalargtjoinsel", ""),
+        (">=", f.ge, "<=", "<", gesel, gejoinsel, ""),
+    ];
+    for &(op, fname, commutator, negator, restrict, join, extra) in &operators {
+        sql += &format!("CREATE OPERATOR {} (LEFTARG = {}, RIGHTARG = {}, {} = {}, COMMUTATOR = {}, NEGATOR = {}, RESTRICT = {}, JOIN = {}{});\n",
+                        op, name, name, keyword, fname, commutator, negator, restrict, join, extra);
+    }
+
+    sql += &format!("CREATE OPERATOR CLASS {}_ops DEFAULT FOR TYPE {} USING btree AS \
+                     OPERATOR 1 <, OPERATOR 2 <=, OPERATOR 3 =, OPERATOR 4 >=, OPERATOR 5 >, FUNCTION 1 {}({});\n",
+                    name, name, f.cmp, args);
+    sql += &format!("CREATE OPERATOR CLASS {}_ops DEFAULT FOR TYPE {} USING hash AS OPERATOR 1 =, FUNCTION 1 {}({});\n",
+                    name, name, f.hash, name);
+    sql
+}
+
+// for a type declared with CREATE_BASE_TYPE! whose Rust type is Ord
+// the hash opclass hashes the payload, so values that compare equal must have the same to_payload()
+// exports the functions and adds $name::create_operators_sql() for the extension script
+// (after create_sql(), the type has to exist)
+#[macro_export]
+macro_rules! CREATE_OPERATORS {
+    ( type $name:ident = $rust:ty {
+        cmp: $cmp:ident @ $cmp_finfo:ident,
+        eq: $eq:ident @ $eq_finfo:ident,
+        ne: $ne:ident @ $ne_finfo:ident,
+        lt: $lt:ident @ $lt_finfo:ident,
+        le: $le:ident @ $le_finfo:ident,
+        gt: $gt:ident @ $gt_finfo:ident,
+        ge: $ge:ident @ $ge_finfo:ident,
+        hash: $hash:ident @ $hash_finfo:ident $(,)*
+    } ) => {
+        impl $name {
+            pub fn create_operators_sql() -> String {
+                $crate::opclass::create_operators_sql(stringify!($name), &$crate::opclass::OperatorFunctions {
+                    cmp: stringify!($cmp),
+                    eq: stringify!($eq),
+                    ne: stringify!($ne),
+                    lt: stringify!($lt),
+                    le: stringify!($le),
+                    gt: stringify!($gt),
+                    ge: stringify!($ge),
+                    hash: stringify!($hash),
+                })
+            }
+        }
+
+        basetype_export!($cmp @ $cmp_finfo => $crate::opclass::call_cmp::<$rust, $name>);
+        basetype_export!($eq @ $eq_finfo => $crate::opclass::call_eq::<$rust, $name>);
+        basetype_export!($ne @ $ne_finfo => $crate::opclass::call_ne::<$rust, $name>);
+        basetype_export!($lt @ $lt_finfo => $crate::opclass::call_lt::<$rust, $name>);
+        basetype_export!($le @ $le_finfo => $crate::opclass::call_le::<$rust, $name>);
+        basetype_export!($gt @ $gt_finfo => $crate::opclass::call_gt::<$rust, $name>);
+        basetype_export!($ge @ $ge_finfo => $crate::opclass::call_ge::<$rust, $name>);
+        basetype_export!($hash @ $hash_finfo => $crate::opclass::call_hash::<$name>);
+    }
+}
@@ -0,0 +1,288 @@
+use std::os::raw::c_void;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::slice;
+
+use Datum;
+use error;
+use alloc::{self, MemoryContext};
+use types::{StaticallyTyped, FromDatum, Oid, int4, int8, numeric};
+use datetime::{Date, Timestamp, TimestampTz};
+use varlena::{self, BaseVarlena, Varlena, Toasted};
+
+// compare utils/rangetypes.h and utils/multirangetypes.h
+
+#[repr(C)]
+struct RangeBound {
+    val: Datum<'static>,
+    infinite: u8,
+    inclusive: u8,
+    lower: u8,
+}
+
+const TYPECACHE_RANGE_INFO: i32 = 0x800;
+
+extern "C" {
+    fn lookup_type_cache(type_id: Oid, flags: i32) -> *mut c_void;
+    fn range_deserialize(typcache: *mut c_void, range: *const u8, lower: *mut RangeBound, upper: *mut RangeBound, empty: *mut u8);
+}
+
+// like range_serialize, but also canonicalizes discrete ranges
+#[cfg(not(escontext))]
+extern "C" {
+    fn make_range(typcache: *mut c_void, lower: *const RangeBound, upper: *const RangeBound, empty: u8) -> *mut u8;
+}
+
+#[cfg(escontext)]
+extern "C" {
+    #[link_name = "make_range"]
+    fn make_range_escontext(typcache: *mut c_void, lower: *const RangeBound, upper: *const RangeBound, empty: u8, escontext: *mut c_void) -> *mut u8;
+}
+
+// errors are raised as usual
+#[cfg(escontext)]
+unsafe fn make_range(typcache: *mut c_void, lower: *const RangeBound, upper: *const RangeBound, empty: u8) -> *mut u8 {
+    make_range_escontext(typcache, lower, upper, empty, ::std::ptr::null_mut())
+}
+
+#[cfg(multirange)]
+extern "C" {
+    fn get_multirange_range(multirange_oid: Oid) -> Oid;
+    fn multirange_get_range(rangetyp: *mut c_void, multirange: *const u8, i: i32) -> *mut u8;
+}
+
+fn range_typcache(range_type: Oid) -> *mut c_void {
+    error::convert_postgres_error(|| unsafe { lookup_type_cache(range_type, TYPECACHE_RANGE_INFO) })
+}
+
+// types that have a builtin range (and, since 14, multirange) type
+pub unsafe trait RangeElement: StaticallyTyped {
+    const RANGE_OID: Oid;
+    const MULTIRANGE_OID: Oid;
+}
+unsafe impl RangeElement for int4 { const RANGE_OID: Oid = Oid(3904); const MULTIRANGE_OID: Oid = Oid(4451); }
+unsafe impl<'a> RangeElement for Toasted<'a, numeric> { const RANGE_OID: Oid = Oid(3906); const MULTIRANGE_OID: Oid = Oid(4532); }
+unsafe impl RangeElement for Timestamp { const RANGE_OID: Oid = Oid(3908); const MULTIRANGE_OID: Oid = Oid(4533); }
+unsafe impl RangeElement for TimestampTz { const RANGE_OID: Oid = Oid(3910); const MULTIRANGE_OID: Oid = Oid(4534); }
+unsafe impl RangeElement for Date { const RANGE_OID: Oid = Oid(3912); const MULTIRANGE_OID: Oid = Oid(4535); }
+unsafe impl RangeElement for int8 { const RANGE_OID: Oid = Oid(3926); const MULTIRANGE_OID: Oid = Oid(4536); }
+
+// the element type is checked by whoever produces the reference (typecheck for function arguments)
+// all accessors need a 4-byte header, which detoast_packed takes care of (see Varlena::ALIGNED)
+pub struct Range<T> {
+    marker: PhantomData<T>,
+    _inner: BaseVarlena,
+}
+
+unsafe impl<T> Varlena for Range<T> {
+    const ALIGNED: bool = true;
+
+    unsafe fn dst_ptrcast<'a, P>(ptr: *const P) -> &'a mut Self {
+        &mut *(slice::from_raw_parts_mut(ptr as *const u8 as *mut u8, 0) as *mut [u8] as *mut Range<T>)
+    }
+}
+
+unsafe impl<T: RangeElement> StaticallyTyped for Range<T> { const OID: Oid = T::RANGE_OID; }
+
+impl<T> Range<T> {
+    fn base_ptr(&self) -> *const u8 {
+        let ptr = self as *const _ as *const u8;
+        assert!(unsafe { varlena::is_4b_uncompressed(ptr) }, "range must be detoasted with detoast_unpacked");
+        ptr
+    }
+
+    // RangeTypeGetOid
+    pub fn range_type(&self) -> Oid {
+        unsafe { *(self.base_ptr().offset(4) as *const Oid) }
+    }
+
+    fn deserialize(&self) -> (RangeBound, RangeBound, bool) {
+        let typcache = range_typcache(self.range_type());
+        let ptr = self.base_ptr();
+        unsafe {
+            let (mut lower, mut upper, mut empty) = (RangeBound::default(), RangeBound::default(), 0u8);
+            let (lower_ptr, upper_ptr, empty_ptr) = (&mut lower as *mut _, &mut upper as *mut _, &mut empty as *mut _);
+            error::convert_postgres_error(|| range_deserialize(typcache, ptr, lower_ptr, upper_ptr, empty_ptr));
+            (lower, upper, empty != 0)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deserialize().2
+    }
+
+    // None for the empty range
+    // by-reference values point into the range
+    pub fn bounds<'a>(&'a self) -> Option<(Bound<T>, Bound<T>)> where T: FromDatum<'a> {
+        let (lower, upper, empty) = self.deserialize();
+        if empty {
+            None
+        } else {
+            Some((lower.to_bound(), upper.to_bound()))
+        }
+    }
+}
+
+impl Default for RangeBound {
+    fn default() -> RangeBound {
+        RangeBound { val: Datum::create(0), infinite: 0, inclusive: 0, lower: 0 }
+    }
+}
+
+impl RangeBound {
+    fn to_bound<'a, T: FromDatum<'a>>(&self) -> Bound<T> {
+        if self.infinite != 0 {
+            Bound::Unbounded
+        } else {
+            let value = unsafe { T::from(Datum::create(self.val.0)) };
+            if self.inclusive != 0 { Bound::Included(value) } else { Bound::Excluded(value) }
+        }
+    }
+
+    fn from_bound<'a, T: Into<Datum<'a>>>(bound: Bound<T>, lower: bool) -> RangeBound {
+        let (val, infinite, inclusive) = match bound {
+            Bound::Included(v) => (Into::<Datum>::into(v).0, false, true),
+            Bound::Excluded(v) => (Into::<Datum>::into(v).0, false, false),
+            Bound::Unbounded => (0, true, false),
+        };
+        RangeBound { val: Datum::create(val), infinite: infinite as u8, inclusive: inclusive as u8, lower: lower as u8 }
+    }
+}
+
+fn serialize<'b, 'c, T: RangeElement>(lower: RangeBound, upper: RangeBound, empty: bool, alloc: &'b MemoryContext<'c>) -> &'b Range<T> {
+    let typcache = range_typcache(T::RANGE_OID);
+    unsafe {
+        let current = alloc::get_current_ctx();
+        alloc.set_current();
+        let ptr = error::convert_postgres_error(|| make_range(typcache, &lower, &upper, empty as u8));
+        current.set_current();
+        Range::dst_ptrcast(ptr)
+    }
+}
+
+impl<'a, T: RangeElement + Into<Datum<'a>>> Range<T> {
+    // canonicalized like any other range value, e.g. [1,5] becomes [1,6) for int4range
+    // raises data_exception if lower > upper
+    pub fn new<'b, 'c>(lower: Bound<T>, upper: Bound<T>, alloc: &'b MemoryContext<'c>) -> &'b Range<T> {
+        serialize(RangeBound::from_bound(lower, true), RangeBound::from_bound(upper, false), false, alloc)
+    }
+
+    pub fn empty<'b, 'c>(alloc: &'b MemoryContext<'c>) -> &'b Range<T> {
+        serialize(RangeBound::from_bound::<T>(Bound::Unbounded, true), RangeBound::from_bound::<T>(Bound::Unbounded, false), true, alloc)
+    }
+
+    // e.g. Range::from_bounds(1..10, alloc) or Range::from_bounds(date.., alloc)
+    pub fn from_bounds<'b, 'c, R: RangeBounds<T>>(bounds: R, alloc: &'b MemoryContext<'c>) -> &'b Range<T> where T: Clone {
+        fn cloned<T: Clone>(b: Bound<&T>) -> Bound<T> {
+            match b {
+                Bound::Included(v) => Bound::Included(v.clone()),
+                Bound::Excluded(v) => Bound::Excluded(v.clone()),
+                Bound::Unbounded => Bound::Unbounded,
+            }
+        }
+        Range::new(cloned(bounds.start_bound()), cloned(bounds.end_bound()), alloc)
+    }
+}
+
+impl<T> Debug for Range<T> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        // same as for arrays, we can only print the raw datums
+        let (lower, upper, empty) = self.deserialize();
+        if empty {
+            write!(fmt, "RANGE(empty)")
+        } else {
+            write!(fmt, "RANGE({:?}, {:?})", lower.to_bound::<Datum>(), upper.to_bound::<Datum>())
+        }
+    }
+}
+
+// 14+ only
+#[cfg(multirange)]
+pub struct Multirange<T> {
+    marker: PhantomData<T>,
+    _inner: BaseVarlena,
+}
+
+#[cfg(multirange)]
+unsafe impl<T> Varlena for Multirange<T> {
+    const ALIGNED: bool = true;
+
+    unsafe fn dst_ptrcast<'a, P>(ptr: *const P) -> &'a mut Self {
+        &mut *(slice::from_raw_parts_mut(ptr as *const u8 as *mut u8, 0) as *mut [u8] as *mut Multirange<T>)
+    }
+}
+
+#[cfg(multirange)]
+unsafe impl<T: RangeElement> StaticallyTyped for Multirange<T> { const OID: Oid = T::MULTIRANGE_OID; }
+
+#[cfg(multirange)]
+impl<T> Multirange<T> {
+    fn base_ptr(&self) -> *const u8 {
+        let ptr = self as *const _ as *const u8;
+        assert!(unsafe { varlena::is_4b_uncompressed(ptr) }, "multirange must be detoasted with detoast_unpacked");
+        ptr
+    }
+
+    // MultirangeTypeGetOid
+    pub fn multirange_type(&self) -> Oid {
+        unsafe { *(self.base_ptr().offset(4) as *const Oid) }
+    }
+
+    pub fn len(&self) -> usize {
+        unsafe { *(self.base_ptr().offset(8) as *const u32) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the ranges are sorted and don't overlap, each one is copied into alloc
+    pub fn ranges<'b, 'c>(&'b self, alloc: &'b MemoryContext<'c>) -> Ranges<'b, 'c, T> {
+        let multirange_type = self.multirange_type();
+        let range_type = error::convert_postgres_error(|| unsafe { get_multirange_range(multirange_type) });
+        Ranges {
+            multirange: self,
+            typcache: range_typcache(range_type),
+            alloc,
+            index: 0,
+        }
+    }
+}
+
+#[cfg(multirange)]
+pub struct Ranges<'b, 'c: 'b, T: 'b> {
+    multirange: &'b Multirange<T>,
+    typcache: *mut c_void,
+    alloc: &'b MemoryContext<'c>,
+    index: usize,
+}
+
+#[cfg(multirange)]
+impl<'b, 'c, T> Iterator for Ranges<'b, 'c, T> {
+    type Item = &'b Range<T>;
+
+    fn next(&mut self) -> Option<&'b Range<T>> {
+        if self.index >= self.multirange.len() {
+            return None;
+        }
+        let (typcache, ptr, i) = (self.typcache, self.multirange.base_ptr(), self.index as i32);
+        self.index += 1;
+        unsafe {
+            // the caller keeps using its own context between items
+            let current = alloc::get_current_ctx();
+            self.alloc.set_current();
+            let range = error::convert_postgres_error(|| multirange_get_range(typcache, ptr, i));
+            current.set_current();
+            Some(Range::dst_ptrcast(range))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.multirange.len() - self.index;
+        (len, Some(len))
+    }
+}
+
+#[cfg(multirange)]
+impl<'b, 'c, T> ExactSizeIterator for Ranges<'b, 'c, T> {}
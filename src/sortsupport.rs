@@ -0,0 +1,206 @@
+use std::os::raw::c_void;
+use std::cmp::Ordering;
+use std::mem;
+
+use Datum;
+use varlena::{Varlena, Toasted};
+use types::FromDatum;
+use alloc::{self, MemoryContext};
+use basetype::{self, BaseType};
+use export::FunctionCallContext;
+use error;
+
+// compare utils/sortsupport.h
+// lets sorts and index builds call our comparator directly instead of going through fmgr,
+// optionally sorting by a pointer-sized abbreviated key first
+
+#[repr(C)]
+struct SortSupportData {
+    ssup_cxt: *mut c_void,
+    ssup_collation: u32,
+    ssup_reverse: u8,
+    ssup_nulls_first: u8,
+    ssup_attno: i16,
+    ssup_extra: *mut c_void,
+    comparator: Option<unsafe extern "C" fn(x: usize, y: usize, ssup: *mut SortSupportData) -> i32>,
+    abbreviate: u8,
+    abbrev_converter: Option<unsafe extern "C" fn(original: usize, ssup: *mut SortSupportData) -> usize>,
+    abbrev_full_comparator: Option<unsafe extern "C" fn(x: usize, y: usize, ssup: *mut SortSupportData) -> i32>,
+    abbrev_abort: Option<unsafe extern "C" fn(memtupcount: i32, ssup: *mut SortSupportData) -> u8>,
+}
+
+// lib/hyperloglog.h
+#[repr(C)]
+struct HyperLogLogState {
+    register_width: u8,
+    n_registers: usize,
+    alpha_mm: f64,
+    hashes_arr: *mut u8,
+    arr_size: usize,
+}
+
+extern "C" {
+    fn initHyperLogLog(state: *mut HyperLogLogState, bwidth: u8);
+    fn addHyperLogLog(state: *mut HyperLogLogState, hash: u32);
+    fn estimateHyperLogLog(state: *mut HyperLogLogState) -> f64;
+}
+
+// hash_uint32 is a static inline wrapper since 13
+#[cfg(hash_bytes)]
+extern "C" {
+    fn hash_bytes_uint32(k: u32) -> u32;
+}
+
+#[cfg(not(hash_bytes))]
+extern "C" {
+    fn hash_uint32(k: u32) -> Datum<'static>;
+}
+
+#[cfg(not(hash_bytes))]
+unsafe fn hash_bytes_uint32(k: u32) -> u32 {
+    hash_uint32(k).0 as u32
+}
+
+pub trait SortSupport: BaseType + Ord {
+    // whether abbreviate is implemented
+    const ABBREVIATE: bool = false;
+
+    // a key that sorts (as an unsigned integer) like the value as far as it goes:
+    // a < b must imply abbreviate(a) <= abbreviate(b), equal keys are resolved by the full comparison
+    fn abbreviate(&self) -> usize {
+        0
+    }
+
+    // asked every now and then once there are enough values, distinct_keys is a HyperLogLog estimate
+    // the default gives up when almost all keys are the same, like numeric does
+    fn abort_abbreviation(input_count: f64, distinct_keys: f64) -> bool {
+        distinct_keys < input_count / 10000.0 + 0.5
+    }
+}
+
+// lives in ssup_cxt, which is reset once the sort is done
+struct SortState {
+    // detoasted copies go here, it's reset after each comparison
+    scratch: *mut c_void,
+    input_count: f64,
+    distinct_keys: HyperLogLogState,
+    estimating: bool,
+}
+
+unsafe fn state<'s>(ssup: *mut SortSupportData) -> &'s mut SortState {
+    &mut *((*ssup).ssup_extra as *mut SortState)
+}
+
+unsafe fn load<T: BaseType, V: Varlena + ?Sized>(d: usize, scratch: &MemoryContext) -> T {
+    let value: Toasted<V> = FromDatum::from(Datum::create(d));
+    basetype::load::<T, V>(value.detoast_packed(scratch))
+}
+
+fn ordering_to_int(o: Ordering) -> i32 {
+    match o {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }
+}
+
+unsafe extern "C" fn comparator<T: SortSupport, V: Varlena + ?Sized>(x: usize, y: usize, ssup: *mut SortSupportData) -> i32 {
+    let mut scratch = alloc::borrow_ctx(state(ssup).scratch);
+    // detoasting switches to scratch, but callers like mergejoin keep allocating in their own context
+    let current = alloc::get_current_ctx();
+    let ret = error::convert_rust_panic(|| {
+        let scratch = &*scratch;
+        load::<T, V>(x, scratch).cmp(&load::<T, V>(y, scratch))
+    });
+    current.set_current();
+    scratch.reset();
+    ordering_to_int(ret)
+}
+
+unsafe extern "C" fn abbrev_comparator(x: usize, y: usize, _ssup: *mut SortSupportData) -> i32 {
+    ordering_to_int(x.cmp(&y))
+}
+
+unsafe extern "C" fn abbrev_converter<T: SortSupport, V: Varlena + ?Sized>(original: usize, ssup: *mut SortSupportData) -> usize {
+    let state = state(ssup);
+    let mut scratch = alloc::borrow_ctx(state.scratch);
+    let current = alloc::get_current_ctx();
+    let key = error::convert_rust_panic(|| load::<T, V>(original, &*scratch).abbreviate());
+    current.set_current();
+    scratch.reset();
+
+    state.input_count += 1.0;
+    if state.estimating {
+        let hash = hash_bytes_uint32(key as u32 ^ (key as u64 >> 32) as u32);
+        addHyperLogLog(&mut state.distinct_keys, hash);
+    }
+    key
+}
+
+// compare numeric_abbrev_abort
+unsafe extern "C" fn abbrev_abort<T: SortSupport>(memtupcount: i32, ssup: *mut SortSupportData) -> u8 {
+    let state = state(ssup);
+    if memtupcount < 10000 || state.input_count < 10000.0 || !state.estimating {
+        return 0;
+    }
+
+    let distinct_keys = estimateHyperLogLog(&mut state.distinct_keys);
+    if distinct_keys > 100000.0 {
+        // this is going well, stop paying for the estimation
+        state.estimating = false;
+        return 0;
+    }
+    let input_count = state.input_count;
+    error::convert_rust_panic(|| T::abort_abbreviation(input_count, distinct_keys)) as u8
+}
+
+#[doc(hidden)]
+pub fn call_sortsupport<'a, T: SortSupport, V: 'a + Varlena + ?Sized>(ctx: &FunctionCallContext<'a>) -> Datum<'a> {
+    unsafe {
+        let ssup = ctx.fcinfo().args_strict()[0].0 as *mut SortSupportData;
+        let sort_ctx = alloc::borrow_ctx((*ssup).ssup_cxt);
+
+        // owned by the sort from now on
+        let scratch = MemoryContext::create_allocset(Some(&*sort_ctx), 0, 8 * 1024, 8 * 1024 * 1024);
+        let state = sort_ctx.alloc(mem::size_of::<SortState>()).as_mut_ptr() as *mut SortState;
+        (*state).scratch = scratch.as_ptr();
+        mem::forget(scratch);
+        (*state).input_count = 0.0;
+        (*state).estimating = true;
+        (*ssup).ssup_extra = state as *mut c_void;
+
+        (*ssup).comparator = Some(comparator::<T, V>);
+        if (*ssup).abbreviate != 0 && T::ABBREVIATE {
+            let hll = &mut (*state).distinct_keys as *mut _;
+            error::convert_postgres_error(|| initHyperLogLog(hll, 10));
+            (*ssup).comparator = Some(abbrev_comparator);
+            (*ssup).abbrev_converter = Some(abbrev_converter::<T, V>);
+            (*ssup).abbrev_full_comparator = Some(comparator::<T, V>);
+            (*ssup).abbrev_abort = Some(abbrev_abort::<T>);
+        }
+    }
+    Datum::create(0)
+}
+
+#[doc(hidden)]
+pub fn create_sort_support_sql(name: &str, sortsupport: &str) -> String {
+    // BTSORTSUPPORT_PROC
+    format!("{}ALTER OPERATOR FAMILY {}_ops USING btree ADD FUNCTION 2 ({}, {}) {}(internal);\n",
+            basetype::create_function_sql(sortsupport, "internal", "void"), name, name, name, sortsupport)
+}
+
+// for a type with CREATE_OPERATORS!, adds the function to its btree operator family
+#[macro_export]
+macro_rules! CREATE_SORT_SUPPORT {
+    ( type $name:ident = $rust:ty {
+        sortsupport: $sortsupport:ident @ $sortsupport_finfo:ident $(,)*
+    } ) => {
+        impl $name {
+            pub fn create_sort_support_sql() -> String {
+                $crate::sortsupport::create_sort_support_sql(stringify!($name), stringify!($sortsupport))
+            }
+        }
+
+        basetype_export!($sortsupport @ $sortsupport_finfo => $crate::sortsupport::call_sortsupport::<$rust, $name>);
+    }
+}
@@ -0,0 +1,44 @@
+// SqlState is generated from errcodes.txt, compare utils/errcodes.h
+include!(concat!(env!("OUT_DIR"), "/sqlstate.rs"));
+
+// compare MAKE_SQLSTATE in elog.h
+pub const fn make_sqlstate(code: &[u8; 5]) -> i32 {
+    let mut ret = 0;
+    let mut i = 0;
+    while i < 5 {
+        ret += ((code[i].wrapping_sub(b'0') & 0x3f) as i32) << (6 * i);
+        i += 1;
+    }
+    ret
+}
+
+impl SqlState {
+    // the raw value as stored in ErrorData.sqlerrcode
+    #[inline]
+    pub fn sqlerrcode(self) -> i32 {
+        self as i32
+    }
+
+    // compare ERRCODE_TO_CATEGORY: the generic "xx000" code of this code's class
+    #[inline]
+    pub fn category(self) -> i32 {
+        self.sqlerrcode() & ((1 << 12) - 1)
+    }
+
+    pub fn class(self) -> &'static str {
+        &self.code()[..2]
+    }
+}
+
+impl From<SqlState> for i32 {
+    fn from(s: SqlState) -> i32 {
+        s.sqlerrcode()
+    }
+}
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+impl Display for SqlState {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str(self.code())
+    }
+}
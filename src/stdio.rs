@@ -0,0 +1,288 @@
+use std::os::raw::c_int;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write, Seek, SeekFrom};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::ffi::CString;
+use std::{env, process};
+
+use error::{self, Level};
+
+// Rust's stdout/stderr can't be hooked on stable, so instead we point fds 1 and 2
+// at unlinked temp files while our functions run. a file (as opposed to a pipe) can never fill up and block the backend.
+// whenever we call back into postgres the real fds are put back, so whatever postgres writes to stderr isn't captured,
+// and the complete lines written so far are forwarded to elog. check_for_interrupts does the same if there's new output,
+// so long-running functions don't have to return first.
+
+extern "C" {
+    fn dup(fd: c_int) -> c_int;
+    fn dup2(oldfd: c_int, newfd: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+}
+
+struct Stream {
+    fd: c_int,
+    original: c_int,
+    file: File,
+    read_pos: u64,
+    partial: Vec<u8>,
+}
+
+struct Capture {
+    level: Level,
+    // opened by the first capture in each process, see open.
+    // under shared_preload_libraries redirect runs in the postmaster and its backends must not share the files
+    streams: Vec<Stream>,
+    pid: Option<u32>,
+    redirected: bool,
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+// same as Capture::redirected, so calls into postgres don't have to lock unless we're capturing
+static REDIRECTED: AtomicBool = AtomicBool::new(false);
+
+fn lock() -> ::std::sync::MutexGuard<'static, Option<Capture>> {
+    CAPTURE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Stream {
+    fn create(fd: c_int) -> io::Result<Stream> {
+        let path = env::temp_dir().join(format!("pg_rust_stdio.{}.{}", process::id(), fd));
+        let open = || OpenOptions::new().read(true).write(true).create_new(true).open(&path);
+        let file = match open() {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                // left behind by a crashed backend that had the same pid
+                fs::remove_file(&path)?;
+                open()?
+            }
+            file => file?,
+        };
+        fs::remove_file(&path)?;
+
+        let original = unsafe { dup(fd) };
+        if original < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Stream {
+            fd,
+            original,
+            file,
+            read_pos: 0,
+            partial: Vec::new(),
+        })
+    }
+
+    fn redirect(&self) {
+        unsafe { dup2(self.file.as_raw_fd(), self.fd); }
+    }
+
+    fn restore(&self) {
+        unsafe { dup2(self.original, self.fd); }
+    }
+
+    // collect everything written since the last call, returning complete lines
+    fn drain(&mut self, everything: bool) -> Vec<CString> {
+        let mut buf = [0u8; 8192];
+        loop {
+            match self.file.read_at(&mut buf, self.read_pos) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    self.partial.extend_from_slice(&buf[..n]);
+                    self.read_pos += n as u64;
+                }
+            }
+        }
+
+        // all caught up: start over so the file doesn't grow forever
+        let _ = self.file.set_len(0).and_then(|_| self.file.seek(SeekFrom::Start(0)));
+        self.read_pos = 0;
+
+        let mut lines = Vec::new();
+        while let Some(newline) = self.partial.iter().position(|&b| b == b'\n') {
+            lines.push(to_cstring(&self.partial[..newline]));
+            self.partial.drain(..newline + 1);
+        }
+        if everything && !self.partial.is_empty() {
+            lines.push(to_cstring(&self.partial));
+            self.partial.clear();
+        }
+        lines
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe { close(self.original); }
+    }
+}
+
+fn to_cstring(line: &[u8]) -> CString {
+    CString::new(String::from_utf8_lossy(line).replace('\0', "\\0")).unwrap()
+}
+
+impl Capture {
+    // Ok(false) if opening failed before
+    fn open(&mut self) -> io::Result<bool> {
+        let pid = process::id();
+        if self.pid == Some(pid) {
+            return Ok(!self.streams.is_empty());
+        }
+
+        // only try once per process, streams inherited from the parent are simply closed
+        self.pid = Some(pid);
+        self.streams.clear();
+        self.streams = vec![Stream::create(1)?, Stream::create(2)?];
+        Ok(true)
+    }
+
+    fn set_redirected(&mut self, redirected: bool) {
+        if redirected == self.redirected {
+            return;
+        }
+        for s in &self.streams {
+            if redirected { s.redirect(); } else { s.restore(); }
+        }
+        self.redirected = redirected;
+        REDIRECTED.store(redirected, Ordering::Relaxed);
+    }
+
+    fn drain(&mut self, everything: bool) -> Vec<CString> {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        self.streams.iter_mut().flat_map(|s| s.drain(everything)).collect()
+    }
+
+    fn has_output(&self) -> bool {
+        self.streams.iter().any(|s| s.file.metadata().map(|m| m.len() > s.read_pos).unwrap_or(false))
+    }
+}
+
+// NB: can longjmp
+unsafe fn forward(level: Level, lines: &[CString]) {
+    for line in lines {
+        error::report_raw(level, line);
+    }
+}
+
+// to be called from _PG_init, this is opt-in
+// level should be Level::Log or Level::Notice
+// the files are created once our functions are called, failing that capturing is skipped with a warning
+pub fn redirect(level: Level) {
+    let mut capture = lock();
+    assert!(capture.is_none(), "stdio is already redirected");
+
+    *capture = Some(Capture {
+        level,
+        streams: Vec::new(),
+        pid: None,
+        redirected: false,
+    });
+}
+
+// postgres gets the real fds back until this is dropped
+#[doc(hidden)]
+pub struct Suspended {
+    level: Level,
+    lines: Vec<CString>,
+}
+
+impl Suspended {
+    // the lines captured up to the suspension
+    // NB: can longjmp
+    pub(crate) unsafe fn forward(&self) {
+        forward(self.level, &self.lines);
+    }
+}
+
+impl Drop for Suspended {
+    fn drop(&mut self) {
+        if let Some(ref mut capture) = *lock() {
+            capture.set_redirected(true);
+        }
+    }
+}
+
+// called by catch_postgres_error
+#[inline]
+pub(crate) fn suspend() -> Option<Suspended> {
+    if REDIRECTED.load(Ordering::Relaxed) { suspend_slow() } else { None }
+}
+
+#[inline(never)]
+fn suspend_slow() -> Option<Suspended> {
+    let mut capture = lock();
+    let capture = capture.as_mut()?;
+    capture.set_redirected(false);
+    Some(Suspended { level: capture.level, lines: capture.drain(false) })
+}
+
+// forward all complete lines written so far
+pub fn flush() {
+    if let Some(suspended) = suspend() {
+        let suspended = &suspended;
+        error::convert_postgres_error(move || unsafe { suspended.forward() });
+    }
+}
+
+// flush if anything was written since the last time, called by check_for_interrupts
+#[inline]
+pub fn poll() {
+    if REDIRECTED.load(Ordering::Relaxed) {
+        poll_slow();
+    }
+}
+
+#[inline(never)]
+fn poll_slow() {
+    let has_output = lock().as_ref().map(|c| c.has_output()).unwrap_or(false);
+    if has_output {
+        flush();
+    }
+}
+
+pub struct CaptureGuard {
+    was_redirected: bool,
+}
+
+// called when entering one of our functions, no-op unless redirect() was called
+#[inline]
+pub fn capture() -> Option<CaptureGuard> {
+    let err = {
+        let mut capture = lock();
+        let capture = capture.as_mut()?;
+        match capture.open() {
+            Ok(true) => {
+                let was_redirected = capture.redirected;
+                capture.set_redirected(true);
+                return Some(CaptureGuard { was_redirected });
+            }
+            Ok(false) => return None,
+            Err(e) => e,
+        }
+    };
+
+    error::report(Level::Warning, &format!("could not capture stdout/stderr: {}", err));
+    None
+}
+
+impl Drop for CaptureGuard {
+    fn drop(&mut self) {
+        let (level, lines) = {
+            let mut capture = lock();
+            let capture = capture.as_mut().unwrap();
+            capture.set_redirected(false);
+            // partial lines are kept if the Rust code that called us is still capturing
+            (capture.level, capture.drain(!self.was_redirected))
+        };
+
+        // elog can throw and we may be unwinding already
+        error::convert_postgres_error_dtor(|| unsafe { forward(level, &lines) });
+
+        if self.was_redirected {
+            lock().as_mut().unwrap().set_redirected(true);
+        }
+    }
+}
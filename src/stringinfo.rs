@@ -0,0 +1,90 @@
+use std::os::raw::{c_char, c_void};
+use std::marker::PhantomData;
+use std::{fmt, mem, slice};
+
+use types::text;
+use encoding;
+use varlena::{self, Varlena};
+use alloc::MemoryContext;
+use error;
+
+// compare lib/stringinfo.h
+
+#[repr(C)]
+struct StringInfoData {
+    data: *mut c_char,
+    len: i32,
+    maxlen: i32,
+    cursor: i32,
+}
+
+extern "C" {
+    fn initStringInfo(s: *mut StringInfoData);
+    fn appendBinaryStringInfo(s: *mut StringInfoData, data: *const c_char, datalen: i32);
+}
+
+// the unread rest of a message, compare pq_getmsgbytes
+// marks everything as consumed, like the receive functions of the builtin types do
+pub(crate) unsafe fn take_remaining<'a>(buf: *mut c_void) -> &'a [u8] {
+    let buf = buf as *mut StringInfoData;
+    let data = slice::from_raw_parts(((*buf).data as *const u8).offset((*buf).cursor as isize),
+                                     ((*buf).len - (*buf).cursor) as usize);
+    (*buf).cursor = (*buf).len;
+    data
+}
+
+// builds a text value incrementally without copying it at the end:
+// the buffer starts with room for the varlena header, just like pq_begintypsend does it
+pub struct TextWriter<'b> {
+    buf: StringInfoData,
+    marker: PhantomData<&'b text>,
+}
+
+impl<'b> TextWriter<'b> {
+    pub fn new<'c>(alloc: &'b MemoryContext<'c>) -> TextWriter<'b> {
+        unsafe {
+            // the buffer is repalloc'd in its own context from here on
+            alloc.set_current();
+            let mut writer = TextWriter { buf: mem::zeroed(), marker: PhantomData };
+            let buf = &mut writer.buf as *mut StringInfoData;
+            error::convert_postgres_error(|| {
+                initStringInfo(buf);
+                appendBinaryStringInfo(buf, [0 as c_char; 4].as_ptr(), 4);
+            });
+            writer
+        }
+    }
+
+    // converts to the server encoding as it goes
+    pub fn push_str(&mut self, s: &str) {
+        let buf = &mut self.buf as *mut StringInfoData;
+        encoding::with_server_encoding(s, |bytes| unsafe {
+            let (ptr, len) = (bytes.as_ptr() as *const c_char, bytes.len() as i32);
+            error::convert_postgres_error(|| appendBinaryStringInfo(buf, ptr, len))
+        })
+    }
+
+    // in bytes, excluding the header
+    pub fn len(&self) -> usize {
+        self.buf.len as usize - 4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn finish(self) -> &'b text {
+        unsafe {
+            let ptr = self.buf.data as *mut u8;
+            varlena::set_varsize(ptr, self.buf.len as usize);
+            text::dst_ptrcast(ptr as *const c_void)
+        }
+    }
+}
+
+impl<'b> fmt::Write for TextWriter<'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
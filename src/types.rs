@@ -2,9 +2,16 @@
 use std::os::raw::c_char;
 
 use super::Datum;
-use varlena::BaseVarlena;
-use alloc::MemoryContext;
+use varlena::{BaseVarlena, Varlena, Toasted};
+use array::Array;
+use range::Range;
+#[cfg(multirange)] use range::Multirange;
+use datetime;
+use alloc::{self, MemoryContext};
 use error;
+use encoding;
+use std::borrow::Cow;
+use sqlstate::SqlState;
 
 mod hack { pub type bool_hack = bool; }
 pub type bool = self::hack::bool_hack;
@@ -12,6 +19,21 @@ pub type char = i8;
 pub type int8 = i64;
 pub type int2 = i16;
 pub type int4 = i32;
+pub type float4 = f32;
+pub type float8 = f64;
+pub type date = datetime::Date;
+pub type time = datetime::Time;
+pub type timetz = datetime::TimeTz;
+pub type timestamp = datetime::Timestamp;
+pub type timestamptz = datetime::TimestampTz;
+pub type interval = datetime::Interval;
+pub type numeric = ::numeric::Numeric;
+pub type jsonb = ::jsonb::Jsonb;
+pub type inet = ::net::Inet;
+pub type cidr = ::net::Cidr;
+pub type macaddr = ::net::MacAddr;
+pub type macaddr8 = ::net::MacAddr8;
+pub type anyenum = ::enums::EnumValue;
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -21,59 +43,41 @@ pub type oid = Oid;
 
 
 
-impl DerefMut for bytea {
-    fn deref_mut(&mut self) -> &mut [u8] {
-        let ptr = self as *mut _ as *mut u8;
-        unsafe {
-            let head8 = *ptr;
-            if (head8 & 0x01) != 0 {
-                panic!("Attempted to write to a bytea with 1-byte header.");
-            }
-
-            let len = *(ptr as *const u32) >> 2; // total size
-            &mut slice::from_raw_parts_mut(ptr, len as usize)[4..] // skip header
-        }
-    }
-}
-
-
 use std::ops::{Deref, DerefMut};
-use std::slice;
 impl Deref for bytea {
     type Target = [u8];
-    
+
     fn deref(&self) -> &[u8] {
-        let ptr = self as *const _ as *mut u8;
-        unsafe {
-            // FIXME: we assume little endian
-            let head8 = *ptr;
-            if (head8 & 0x01) != 0 {
-                // is 1b
-                let len = head8 >> 1; // total size
-                &slice::from_raw_parts(ptr, len as usize)[1..] // skip header
-            } else {
-                // is 4b
-                let len = *(ptr as *const u32) >> 2; // total size
-                &slice::from_raw_parts(ptr, len as usize)[4..] // skip header
-            }
-        }
+        unsafe { ::varlena::payload(self as *const _ as *const u8) }
+    }
+}
+
+// panics on toasted values, see varlena::make_mut
+impl DerefMut for bytea {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { ::varlena::payload_mut(self as *mut _ as *mut u8) }
     }
 }
 
 pub struct bytea(BaseVarlena);
 pub struct text(BaseVarlena);
 
-#[repr(i32)]
-enum PgEncoding {
-    SqlAscii = 0,
-    Utf8 = 6,
-    // rest is todo
-    // fixme: generate these from pgbuild
+// copies the value into a new palloc'd varlena with room for pad trailing spaces
+pub(crate) fn alloc_string_varlena<'b, 'c>(value: &str, pad: usize, alloc: &'b MemoryContext<'c>) -> *const u8 {
+    encoding::with_server_encoding(value, |bytes| {
+        let size = 4 + bytes.len() + pad;
+        let buf = alloc.alloc(size);
+        buf[4..4 + bytes.len()].copy_from_slice(bytes);
+        for b in buf[4 + bytes.len()..].iter_mut() {
+            *b = b' ';
+        }
+        unsafe { ::varlena::set_varsize(buf.as_mut_ptr(), size); }
+        buf.as_ptr()
+    })
 }
 
-extern "C" {
-    fn GetDatabaseEncoding() -> i32;
-    fn pg_server_to_any(s: *const c_char, len: i32, encoding: i32) -> *const c_char;
+pub fn text_from_str<'b, 'c>(value: &str, alloc: &'b MemoryContext<'c>) -> &'b text {
+    unsafe { text::dst_ptrcast(alloc_string_varlena(value, 0, alloc)) }
 }
 
 
@@ -81,53 +85,245 @@ extern "C" {
 use std::str;
 use std::ffi::CStr;
 impl text {
-    pub fn to_string<'a, 'b>(&'a self, alloc: &'b MemoryContext) -> Result<&'a str, &'b mut str> {
-        unsafe {
-            let my_data: &bytea = ::std::mem::transmute(self);
-            let myptr = my_data.as_ptr() as *const c_char;
-            alloc.set_current();
-            let converted = error::convert_postgres_error(|| pg_server_to_any(myptr, my_data.len() as i32, PgEncoding::Utf8 as i32));
-            if converted == myptr {
-                Ok(str::from_utf8_unchecked(my_data))
-            } else {
-                // poor man's strlen (can't do this naturally through CStr - would have to mut-transmute the &str)
-                let newlen = CStr::from_ptr(converted).to_bytes().len();
-                Err(str::from_utf8_unchecked_mut(slice::from_raw_parts_mut(converted as *mut u8, newlen)))
+    // raises untranslatable_character if the database encoding can't be converted to UTF-8
+    pub fn to_string(&self) -> Cow<str> {
+        encoding::server_to_utf8(self.as_bytes()).unwrap_or_else(|e| e.raise())
+    }
+
+    // like to_string, but returns None instead of raising an error
+    pub fn to_str(&self) -> Option<Cow<str>> {
+        encoding::server_to_utf8(self.as_bytes()).ok()
+    }
+
+    // the raw payload in the database encoding
+    pub fn as_bytes(&self) -> &[u8] {
+        let my_data: &bytea = unsafe { ::std::mem::transmute(self) };
+        my_data
+    }
+}
+
+// char(n) and varchar(n) are stored just like text
+// bpchar is blank-padded to its declared length, trailing spaces are insignificant
+pub struct varchar(BaseVarlena);
+pub struct bpchar(BaseVarlena);
+
+impl Deref for varchar {
+    type Target = text;
+    fn deref(&self) -> &text { unsafe { ::std::mem::transmute(self) } }
+}
+impl Deref for bpchar {
+    type Target = text;
+    fn deref(&self) -> &text { unsafe { ::std::mem::transmute(self) } }
+}
+
+const VARHDRSZ: i32 = 4;
+
+// compare varchar_input/bpchar_input: typmod is the declared length + VARHDRSZ (or -1 for none)
+// returns the part of the value to keep and how many spaces to pad it with
+fn fit_length<'s>(value: &'s str, typmod: i32, pad: bool, type_name: &str) -> (&'s str, usize) {
+    if typmod < VARHDRSZ {
+        return (value, 0);
+    }
+    let maxlen = (typmod - VARHDRSZ) as usize;
+    match value.char_indices().nth(maxlen) {
+        Some((cut, _)) => {
+            // only trailing spaces may be cut off
+            if value[cut..].bytes().any(|b| b != b' ') {
+                error::raise(SqlState::StringDataRightTruncation,
+                             format!("value too long for type {}({})", type_name, maxlen));
             }
+            (&value[..cut], 0)
         }
+        None if pad => (value, maxlen - value.chars().count()),
+        None => (value, 0),
     }
+}
 
-    // edgecase: for invalid utf, to_string should error while this just returns None
-    // is that a problem?
-    pub fn to_str(&self) -> Option<&str> {
-        unsafe {
-            let my_data: &bytea = ::std::mem::transmute(self);
-            let encoding = GetDatabaseEncoding();
-            if encoding == PgEncoding::Utf8 as i32 {
-                Some(str::from_utf8_unchecked(my_data))
-            } else if encoding == PgEncoding::SqlAscii as i32 {
-                str::from_utf8(my_data).ok()
-            } else {
-                None
-            }
+impl varchar {
+    // raises string_data_right_truncation if the value is too long, just like a cast would
+    pub fn new<'b, 'c>(value: &str, typmod: i32, alloc: &'b MemoryContext<'c>) -> &'b varchar {
+        let (value, _) = fit_length(value, typmod, false, "character varying");
+        unsafe { varchar::dst_ptrcast(alloc_string_varlena(value, 0, alloc)) }
+    }
+}
+
+impl bpchar {
+    // pads with spaces up to the declared length
+    pub fn new<'b, 'c>(value: &str, typmod: i32, alloc: &'b MemoryContext<'c>) -> &'b bpchar {
+        let (value, pad) = fit_length(value, typmod, true, "character");
+        unsafe { bpchar::dst_ptrcast(alloc_string_varlena(value, pad, alloc)) }
+    }
+
+    // like to_str, but without the padding
+    pub fn to_str_trimmed(&self) -> Option<Cow<str>> {
+        self.to_str().map(|s| match s {
+            Cow::Borrowed(s) => Cow::Borrowed(s.trim_end_matches(' ')),
+            Cow::Owned(s) => Cow::Owned(s.trim_end_matches(' ').to_owned()),
+        })
+    }
+}
+
+// fixed-size but passed by reference
+#[repr(C)]
+pub struct NameData {
+    data: [c_char; ::NAMEDATALEN],
+}
+pub type name = NameData;
+
+impl NameData {
+    pub fn as_cstr(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.data.as_ptr()) }
+    }
+
+    // names are in the database encoding, just like text
+    pub fn to_str(&self) -> Option<Cow<str>> {
+        encoding::server_to_utf8(self.as_cstr().to_bytes()).ok()
+    }
+
+    // truncates to NAMEDATALEN - 1 bytes like namein does (but not mid-character)
+    pub fn set(&mut self, value: &str) {
+        let mut len = ::std::cmp::min(value.len(), ::NAMEDATALEN - 1);
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+        assert!(!value.as_bytes()[..len].contains(&0), "name must not contain nul bytes");
+
+        for (d, &s) in self.data.iter_mut().zip(value.as_bytes()[..len].iter()) {
+            *d = s as c_char;
+        }
+        for d in self.data[len..].iter_mut() {
+            *d = 0;
         }
     }
 }
 
-pub type name = [c_char; 64]; // FIXME wtf
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+impl Debug for NameData {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        Debug::fmt(self.as_cstr(), fmt)
+    }
+}
+
+// fixed-size but passed by reference, copied out like the date/time types
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid([u8; 16]);
+pub type uuid = Uuid;
+
+impl Uuid {
+    pub fn from_bytes(bytes: [u8; 16]) -> Uuid { Uuid(bytes) }
+    pub fn as_bytes(&self) -> &[u8; 16] { &self.0 }
+}
+
+impl From<[u8; 16]> for Uuid { fn from(bytes: [u8; 16]) -> Uuid { Uuid(bytes) } }
+impl From<Uuid> for [u8; 16] { fn from(u: Uuid) -> [u8; 16] { u.0 } }
+
+use std::fmt::Display;
+impl Display for Uuid {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        for (i, b) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(fmt, "-")?;
+            }
+            write!(fmt, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+impl Debug for Uuid {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Uuid({})", self)
+    }
+}
 
 pub type void = ();
 
 impl_varlena!(text);
 impl_varlena!(bytea);
+impl_varlena!(varchar);
+impl_varlena!(bpchar);
 
 
 impl<'a> From<oid> for Datum<'a> { fn from(i: oid) -> Datum<'a> { Datum::create(i.0 as usize) } }
 impl<'a> FromDatum<'a> for oid { unsafe fn from(d: Datum<'a>) -> oid { Oid(d.0 as u32) } }
-impl<'a> From<int8> for Datum<'a> { fn from(i: i64) -> Datum<'a> { Datum::create(i as usize) } }
-impl<'a> FromDatum<'a> for int8 { unsafe fn from(d: Datum<'a>) -> i64 { d.0 as i64 } }
+impl<'a> From<int8> for Datum<'a> { fn from(i: i64) -> Datum<'a> { int64_to_datum(i) } }
+impl<'a> FromDatum<'a> for int8 { unsafe fn from(d: Datum<'a>) -> i64 { int64_from_datum(d) } }
 impl<'a> From<int4> for Datum<'a> { fn from(i: i32) -> Datum<'a> { Datum::create(i as usize) } }
 impl<'a> FromDatum<'a> for int4 { unsafe fn from(d: Datum<'a>) -> i32 { d.0 as i32 } }
+impl<'a> From<int2> for Datum<'a> { fn from(i: i16) -> Datum<'a> { Datum::create(i as usize) } }
+impl<'a> FromDatum<'a> for int2 { unsafe fn from(d: Datum<'a>) -> i16 { d.0 as i16 } }
+impl<'a> From<char> for Datum<'a> { fn from(c: i8) -> Datum<'a> { Datum::create(c as usize) } }
+impl<'a> FromDatum<'a> for char { unsafe fn from(d: Datum<'a>) -> i8 { d.0 as i8 } }
+impl<'a> From<bool> for Datum<'a> { fn from(b: bool) -> Datum<'a> { Datum::create(b as usize) } }
+impl<'a> FromDatum<'a> for bool { unsafe fn from(d: Datum<'a>) -> bool { (d.0 as u8) != 0 } } // DatumGetBool only looks at the low byte
+
+// floats are byval unless the datum is too small (or postgres was configured that way)
+// in that case they live in a palloc'd chunk in the current memory context, just like Float8GetDatum does it
+pub(crate) unsafe fn palloc_datum<'a, T>(value: T) -> Datum<'a> {
+    let ptr = error::convert_postgres_error(|| alloc::get_current_ctx().alloc_undef(::std::mem::size_of::<T>())) as *mut T;
+    ptr.write(value);
+    Datum::create(ptr as usize)
+}
+
+// int64 datums are byval exactly when float8 ones are
+pub(crate) unsafe fn int64_from_datum(d: Datum) -> i64 {
+    if ::FLOAT8_BYVAL {
+        d.0 as i64
+    } else {
+        *(d.0 as *const i64)
+    }
+}
+
+pub(crate) fn int64_to_datum<'a>(i: i64) -> Datum<'a> {
+    if ::FLOAT8_BYVAL {
+        Datum::create(i as usize)
+    } else {
+        unsafe { palloc_datum(i) }
+    }
+}
+
+impl<'a> From<float4> for Datum<'a> {
+    fn from(f: f32) -> Datum<'a> {
+        if ::FLOAT4_BYVAL {
+            Datum::create(f.to_bits() as i32 as usize)
+        } else {
+            unsafe { palloc_datum(f) }
+        }
+    }
+}
+impl<'a> FromDatum<'a> for float4 {
+    unsafe fn from(d: Datum<'a>) -> f32 {
+        if ::FLOAT4_BYVAL {
+            f32::from_bits(d.0 as u32)
+        } else {
+            *(d.0 as *const f32)
+        }
+    }
+}
+impl<'a> From<float8> for Datum<'a> {
+    fn from(f: f64) -> Datum<'a> {
+        if ::FLOAT8_BYVAL {
+            Datum::create(f.to_bits() as usize)
+        } else {
+            unsafe { palloc_datum(f) }
+        }
+    }
+}
+impl<'a> FromDatum<'a> for float8 {
+    unsafe fn from(d: Datum<'a>) -> f64 {
+        if ::FLOAT8_BYVAL {
+            f64::from_bits(d.0 as u64)
+        } else {
+            *(d.0 as *const f64)
+        }
+    }
+}
+
+impl<'a> From<uuid> for Datum<'a> { fn from(u: uuid) -> Datum<'a> { unsafe { palloc_datum(u) } } }
+impl<'a> FromDatum<'a> for uuid { unsafe fn from(d: Datum<'a>) -> uuid { *(d.0 as *const uuid) } }
+
+impl<'a> From<&'a name> for Datum<'a> { fn from(n: &'a name) -> Datum<'a> { Datum::create(n as *const _ as usize) } }
+impl<'a> FromDatum<'a> for &'a name { unsafe fn from(d: Datum<'a>) -> &'a name { &*(d.0 as *const name) } }
 
 // can't use regular(safe) From:
 // e.g. datum -> bytea imples that datum is a valid ptr
@@ -147,12 +343,47 @@ unsafe impl StaticallyTyped for int2 { const OID: Oid = Oid(21); }
 unsafe impl StaticallyTyped for int4 { const OID: Oid = Oid(23); }
 unsafe impl StaticallyTyped for text { const OID: Oid = Oid(25); }
 unsafe impl StaticallyTyped for Oid { const OID: Oid = Oid(26); }
+unsafe impl StaticallyTyped for float4 { const OID: Oid = Oid(700); }
+unsafe impl StaticallyTyped for float8 { const OID: Oid = Oid(701); }
+unsafe impl StaticallyTyped for bpchar { const OID: Oid = Oid(1042); }
+unsafe impl StaticallyTyped for varchar { const OID: Oid = Oid(1043); }
+unsafe impl StaticallyTyped for uuid { const OID: Oid = Oid(2950); }
 
 // void type:
 impl<'a> From<void> for Datum<'a> { fn from(_: ()) -> Datum<'a> { Datum::create(0) } }
 impl<'a> FromDatum<'a> for void { unsafe fn from(_: Datum<'a>) { } }
 unsafe impl StaticallyTyped for void { const OID: Oid = Oid(2278); }
 
+// raw datums, e.g. for debug output
+impl<'a> FromDatum<'a> for Datum<'a> { unsafe fn from(d: Datum<'a>) -> Datum<'a> { d } }
+
+unsafe impl<'a, T: 'a + StaticallyTyped + Varlena + ?Sized> StaticallyTyped for Toasted<'a, T> { const OID: Oid = T::OID; }
+unsafe impl<'a> StaticallyTyped for &'a name { const OID: Oid = Oid(19); }
+
+pub type boolarray = Array<bool>;
+pub type byteaarray<'a> = Array<Toasted<'a, bytea>>;
+pub type chararray = Array<char>;
+pub type int2array = Array<int2>;
+pub type int4array = Array<int4>;
+pub type textarray<'a> = Array<Toasted<'a, text>>;
+pub type int8array = Array<int8>;
+pub type float4array = Array<float4>;
+pub type float8array = Array<float8>;
+pub type oidarray = Array<oid>;
+
+pub type int4range = Range<int4>;
+pub type numrange<'a> = Range<Toasted<'a, numeric>>;
+pub type tsrange = Range<timestamp>;
+pub type tstzrange = Range<timestamptz>;
+pub type daterange = Range<date>;
+pub type int8range = Range<int8>;
+#[cfg(multirange)] pub type int4multirange = Multirange<int4>;
+#[cfg(multirange)] pub type nummultirange<'a> = Multirange<Toasted<'a, numeric>>;
+#[cfg(multirange)] pub type tsmultirange = Multirange<timestamp>;
+#[cfg(multirange)] pub type tstzmultirange = Multirange<timestamptz>;
+#[cfg(multirange)] pub type datemultirange = Multirange<date>;
+#[cfg(multirange)] pub type int8multirange = Multirange<int8>;
+
 
 
 
@@ -174,33 +405,14 @@ unsafe impl StaticallyTyped for void { const OID: Oid = Oid(2278); }
 #define BOXOID                  603
 #define POLYGONOID              604
 #define LINEOID                 628
-#define FLOAT4OID 700
-#define FLOAT8OID 701
 #define ABSTIMEOID              702
 #define RELTIMEOID              703
 #define TINTERVALOID    704
 #define UNKNOWNOID              705
 #define CIRCLEOID               718
 #define CASHOID 790
-#define MACADDROID 829
-#define INETOID 869
-#define CIDROID 650
-#define INT2ARRAYOID            1005
-#define INT4ARRAYOID            1007
-#define TEXTARRAYOID            1009
-#define OIDARRAYOID                     1028  
-#define FLOAT4ARRAYOID 1021
 #define ACLITEMOID              1033
 #define CSTRINGARRAYOID         1263
-#define BPCHAROID               1042
-#define VARCHAROID              1043
-#define DATEOID                 1082
-#define TIMEOID                 1083
-#define TIMESTAMPOID    1114
-#define TIMESTAMPTZOID  1184
-#define INTERVALOID             1186
-#define TIMETZOID               1266
 #define BITOID   1560
 #define VARBITOID         1562
-#define NUMERICOID              1700
 */
@@ -1,8 +1,9 @@
 use std::marker::PhantomData;
 
 use Datum;
-use types::FromDatum;
+use types::{FromDatum, Oid};
 use alloc::MemoryContext;
+use error;
 
 // compare postgres.h for documentation on how this format works
 
@@ -14,6 +15,10 @@ pub struct BaseVarlena {
 }
 
 pub unsafe trait Varlena {
+    // the payload is read in place as aligned fields (e.g. arrays), so a 1-byte header won't do
+    // detoast_packed and to_varlena treat such types like detoast_unpacked
+    const ALIGNED: bool = false;
+
     unsafe fn dst_ptrcast<'a, P>(ptr: *const P) -> &'a mut Self;
 }
 
@@ -21,6 +26,15 @@ pub unsafe trait Varlena {
 macro_rules! impl_varlena {
     ($name:ident) => {
         unsafe impl $crate::varlena::Varlena for $name {
+            unsafe fn dst_ptrcast<'a, P>(ptr: *const P) -> &'a mut Self {
+                &mut *(::std::slice::from_raw_parts_mut(ptr as *const u8 as *mut u8, 0) as *mut _ as *mut _)
+            }
+        }
+    };
+    (aligned $name:ident) => {
+        unsafe impl $crate::varlena::Varlena for $name {
+            const ALIGNED: bool = true;
+
             unsafe fn dst_ptrcast<'a, P>(ptr: *const P) -> &'a mut Self {
                 &mut *(::std::slice::from_raw_parts_mut(ptr as *const u8 as *mut u8, 0) as *mut _ as *mut _)
             }
@@ -35,6 +49,52 @@ enum Header {
     LargeCompressed(u32), // 4B_C
 }
 
+// enum vartag_external
+const VARTAG_INDIRECT: u8 = 1;
+const VARTAG_EXPANDED_RO: u8 = 2;
+const VARTAG_EXPANDED_RW: u8 = 3;
+const VARTAG_ONDISK: u8 = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionMethod {
+    Pglz,
+    Lz4, // 14+
+}
+
+// where and how a value is stored, compare toast_raw_datum_size and toast_datum_size
+// sizes are in bytes and exclude the varlena header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    Inline { size: usize, short_header: bool },
+    Compressed { raw_size: usize, compressed_size: usize, method: CompressionMethod },
+    // in the toast table (compressed if there's a method)
+    External { raw_size: usize, stored_size: usize, method: Option<CompressionMethod>, toast_relid: Oid, value_id: Oid },
+    // pointers to in-memory values
+    Indirect,
+    Expanded { read_write: bool },
+    // an external pointer with a vartag we don't know (yet)
+    Unknown(u8),
+}
+
+// va_tcinfo and va_extinfo have the method in their upper two bits since 14
+fn compression_method(info: u32) -> CompressionMethod {
+    if ::PG_VERSION >= 1400 && info >> 30 == 1 {
+        CompressionMethod::Lz4
+    } else {
+        CompressionMethod::Pglz
+    }
+}
+
+// varatt_external, stored unaligned
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct VarattExternal {
+    rawsize: i32, // including the header
+    extinfo: u32, // va_extsize before 14
+    valueid: Oid,
+    toastrelid: Oid,
+}
+
 #[repr(C)]
 pub struct Toasted<'a, T: 'a + Varlena + ?Sized> {
     ptr: *const u8,
@@ -73,12 +133,14 @@ impl<'a, T: 'a + Varlena + ?Sized> From<&'a mut T> for Toasted<'a, T> {
 
 extern "C" {
     fn pg_detoast_datum_copy(datum: *const u8) -> *mut u8;
+    fn pg_detoast_datum_slice(datum: *const u8, sliceoffset: i32, slicelength: i32) -> *mut u8;
 }
 
 impl<'a, T: 'a + Varlena + ?Sized> Toasted<'a, T> {
     pub fn to_varlena(&self) -> Option<&'a T> {
         unsafe {
             match self.header() {
+                Header::Small(_) if T::ALIGNED => None,
                 Header::Small(_) | Header::Large(_) => {
                     let r: &'a T = T::dst_ptrcast(self.ptr);
                     Some(r)
@@ -88,22 +150,170 @@ impl<'a, T: 'a + Varlena + ?Sized> Toasted<'a, T> {
         }
     }
 
+    // always has a 4-byte header
     pub fn copy_detoast<'b, 'c>(&self, allocator: &'b MemoryContext<'c>) -> &'b mut T {
+        let ptr = self.ptr;
         unsafe {
             allocator.set_current();
-            T::dst_ptrcast(pg_detoast_datum_copy(self.ptr))
+            T::dst_ptrcast(error::convert_postgres_error(|| pg_detoast_datum_copy(ptr)))
         }
     }
 
+    // copies just the given byte range of the payload, fetching (and decompressing)
+    // no more of an external value than necessary
+    // the result is shorter if the value ends early, for text the range may well split a character
+    pub fn slice<'b, 'c>(&self, offset: usize, len: usize, allocator: &'b MemoryContext<'c>) -> &'b mut T {
+        assert!(offset <= i32::max_value() as usize, "slice offset out of range");
+        let len = ::std::cmp::min(len, i32::max_value() as usize);
+        let ptr = self.ptr;
+        unsafe {
+            allocator.set_current();
+            T::dst_ptrcast(error::convert_postgres_error(|| pg_detoast_datum_slice(ptr, offset as i32, len as i32)))
+        }
+    }
+
+    // same as detoast_unpacked for ALIGNED types
     pub fn detoast_packed<'b, 'c, 'd>(&self, allocator: &'c MemoryContext<'d>) -> &'b T where 'c: 'b, 'a: 'b {
         self.to_varlena().unwrap_or_else(|| self.copy_detoast(allocator))
     }
 
+    // like detoast_packed but also expands 1-byte headers (compare pg_detoast_datum)
+    // this is required for types that need their fields aligned, e.g. arrays
+    pub fn detoast_unpacked<'b, 'c, 'd>(&self, allocator: &'c MemoryContext<'d>) -> &'b T where 'c: 'b, 'a: 'b {
+        unsafe {
+            match self.header() {
+                Header::Large(_) => T::dst_ptrcast(self.ptr),
+                _ => self.copy_detoast(allocator),
+            }
+        }
+    }
+
+    pub fn storage(&self) -> Storage {
+        unsafe {
+            match self.header() {
+                Header::Small(len) => Storage::Inline { size: len as usize - 1, short_header: true },
+                Header::Large(len) => Storage::Inline { size: len as usize - 4, short_header: false },
+                Header::LargeCompressed(len) => {
+                    let tcinfo = (self.ptr.offset(4) as *const u32).read_unaligned();
+                    Storage::Compressed {
+                        raw_size: (tcinfo & 0x3FFFFFFF) as usize,
+                        // VARHDRSZ_COMPRESSED, the header is followed by va_tcinfo
+                        compressed_size: len as usize - 8,
+                        method: compression_method(tcinfo),
+                    }
+                }
+                Header::External => match *self.ptr.offset(1) {
+                    VARTAG_ONDISK => {
+                        let ext = (self.ptr.offset(2) as *const VarattExternal).read_unaligned();
+                        let raw_size = ext.rawsize as usize - 4;
+                        let stored_size = (ext.extinfo & 0x3FFFFFFF) as usize;
+                        Storage::External {
+                            raw_size,
+                            stored_size,
+                            method: if stored_size < raw_size { Some(compression_method(ext.extinfo)) } else { None },
+                            toast_relid: ext.toastrelid,
+                            value_id: ext.valueid,
+                        }
+                    }
+                    VARTAG_INDIRECT => Storage::Indirect,
+                    VARTAG_EXPANDED_RO => Storage::Expanded { read_write: false },
+                    VARTAG_EXPANDED_RW => Storage::Expanded { read_write: true },
+                    tag => Storage::Unknown(tag),
+                },
+            }
+        }
+    }
+
+    pub fn is_expanded(&self) -> bool {
+        match self.storage() {
+            Storage::Expanded { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
     unsafe fn header(&self) -> Header {
         header(self.ptr)
     }
 }
 
+// i.e. VARATT_IS_4B_U, the only form whose payload is aligned
+pub(crate) unsafe fn is_4b_uncompressed(ptr: *const u8) -> bool {
+    match header(ptr) {
+        Header::Large(_) => true,
+        _ => false,
+    }
+}
+
+// VARDATA_ANY, for values that have been detoasted (but may still be packed)
+pub(crate) unsafe fn payload<'a>(ptr: *const u8) -> &'a [u8] {
+    match header(ptr) {
+        Header::Small(len) => ::std::slice::from_raw_parts(ptr.offset(1), len as usize - 1),
+        Header::Large(len) => ::std::slice::from_raw_parts(ptr.offset(4), len as usize - 4),
+        _ => panic!("varlena is still toasted"),
+    }
+}
+
+// the mutable counterpart of payload, the length can't change so the header stays as it is
+pub(crate) unsafe fn payload_mut<'a>(ptr: *mut u8) -> &'a mut [u8] {
+    match header(ptr) {
+        Header::Small(len) => ::std::slice::from_raw_parts_mut(ptr.offset(1), len as usize - 1),
+        Header::Large(len) => ::std::slice::from_raw_parts_mut(ptr.offset(4), len as usize - 4),
+        _ => panic!("varlena is still toasted, use varlena::make_mut"),
+    }
+}
+
+// SET_VARSIZE, i.e. a 4-byte header for an uncompressed value of the given total size
+#[cfg(target_endian = "little")]
+pub unsafe fn set_varsize(ptr: *mut u8, size: usize) {
+    debug_assert!(size <= 0x3FFFFFFF);
+    *(ptr as *mut u32) = (size as u32) << 2;
+}
+
+#[cfg(target_endian = "big")]
+pub unsafe fn set_varsize(ptr: *mut u8, size: usize) {
+    debug_assert!(size <= 0x3FFFFFFF);
+    *(ptr as *mut u32) = (size as u32) & 0x3FFFFFFF;
+}
+
+// SET_VARSIZE_SHORT, a 1-byte header for a total size of at most 127 bytes
+#[cfg(target_endian = "little")]
+pub unsafe fn set_varsize_short(ptr: *mut u8, size: usize) {
+    debug_assert!(size <= 0x7F);
+    *ptr = ((size as u8) << 1) | 0x01;
+}
+
+#[cfg(target_endian = "big")]
+pub unsafe fn set_varsize_short(ptr: *mut u8, size: usize) {
+    debug_assert!(size <= 0x7F);
+    *ptr = (size as u8) | 0x80;
+}
+
+// a copy in alloc that we may write to, from a &T or a Toasted (which is detoasted as needed)
+// it always has a 4-byte header, even if the original was packed
+pub fn make_mut<'a, 'b, 'c, T: 'a + Varlena + ?Sized, V: Into<Toasted<'a, T>>>(value: V, alloc: &'b MemoryContext<'c>) -> &'b mut T {
+    value.into().copy_detoast(alloc)
+}
+
+// compare VARSIZE_ANY
+pub(crate) unsafe fn varsize_any(ptr: *const u8) -> usize {
+    match header(ptr) {
+        Header::External => {
+            // VARHDRSZ_EXTERNAL + VARTAG_SIZE
+            let tag_size = match *ptr.offset(1) {
+                VARTAG_ONDISK => 16, // varatt_external
+                _ => ::std::mem::size_of::<usize>(), // VARTAG_INDIRECT, VARTAG_EXPANDED_*: just a pointer
+            };
+            2 + tag_size
+        }
+        Header::Small(len) => len as usize,
+        Header::Large(len) | Header::LargeCompressed(len) => len as usize,
+    }
+}
+
 #[cfg(target_endian = "little")]
 unsafe fn header(ptr: *const u8) -> Header {
     let first = *ptr;